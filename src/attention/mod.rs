@@ -1,68 +1,148 @@
-use crate::{tokenizer::DLLToken, utils::MatrixF32};
-use std::{cell::RefCell, rc::Rc};
+use crate::{
+    autograd::{Param, ParamMut, Tape, Var},
+    embedder::Embedder,
+    utils::MatrixF32,
+};
 
 pub struct AttentionParams {
-    dim: i32,
-    w_q: MatrixF32,
-    w_k: MatrixF32,
-    w_v: MatrixF32,
-    w_o: MatrixF32,
+    pub dim: i32,
+    pub n_heads: i32,
+    pub w_q: MatrixF32,
+    pub w_k: MatrixF32,
+    pub w_v: MatrixF32,
+    pub w_o: MatrixF32,
 }
 
 impl AttentionParams {
-    pub fn new(dim: usize) -> Self {
+    pub fn new(dim: usize, n_heads: usize) -> Self {
+        if n_heads == 0 || !dim.is_multiple_of(n_heads) {
+            panic!("[attention_params] dim must be divisible by n_heads");
+        }
+
         Self {
             dim: dim as i32,
+            n_heads: n_heads as i32,
             w_q: MatrixF32::new_rand_weight(dim, dim),
             w_k: MatrixF32::new_rand_weight(dim, dim),
             w_v: MatrixF32::new_rand_weight(dim, dim),
             w_o: MatrixF32::new_rand_weight(dim, dim),
         }
     }
+
+    pub fn params<'a>(&'a self, prefix: &str, params: &mut Vec<Param<'a>>) {
+        params.push(Param::matrix(format!("{}.w_q", prefix), &self.w_q));
+        params.push(Param::matrix(format!("{}.w_k", prefix), &self.w_k));
+        params.push(Param::matrix(format!("{}.w_v", prefix), &self.w_v));
+        params.push(Param::matrix(format!("{}.w_o", prefix), &self.w_o));
+    }
+
+    pub fn params_mut<'a>(&'a mut self, prefix: &str, params: &mut Vec<ParamMut<'a>>) {
+        params.push(ParamMut::matrix(format!("{}.w_q", prefix), &mut self.w_q));
+        params.push(ParamMut::matrix(format!("{}.w_k", prefix), &mut self.w_k));
+        params.push(ParamMut::matrix(format!("{}.w_v", prefix), &mut self.w_v));
+        params.push(ParamMut::matrix(format!("{}.w_o", prefix), &mut self.w_o));
+    }
 }
 
+// Keys and values of the tokens a block has already seen, so each decoding
+// step only has to project the newest tokens.
+pub struct KVCache {
+    pub keys: MatrixF32,
+    pub values: MatrixF32,
+}
+
+impl KVCache {
+    pub fn new(dim: i32) -> Self {
+        Self {
+            keys: MatrixF32::new(0, dim),
+            values: MatrixF32::new(0, dim),
+        }
+    }
+
+    fn append(self: &mut KVCache, keys: &MatrixF32, values: &MatrixF32) {
+        append_rows(&mut self.keys, keys);
+        append_rows(&mut self.values, values);
+    }
+}
+
+fn append_rows(cache: &mut MatrixF32, rows: &MatrixF32) {
+    if cache.cols != rows.cols {
+        panic!("[kv_cache] rows don't match the cache dim");
+    }
+
+    cache.vals.extend_from_slice(&rows.vals);
+    cache.rows += rows.rows;
+}
+
+// Embeds up to `seq_len` tokens of `tokens`; each input position is paired
+// with the token that follows it as the prediction target.
+//
+// With a `pad_id`, shorter windows are padded out to `seq_len`. The padding
+// comes after the real tokens, so under the causal mask it can't change
+// their outputs, and it gets no targets.
 pub fn generate_seq_matrix(
+    tape: &mut Tape,
     seq_len: i32,
-    dim: i32,
-    seq_dll_head: &Rc<RefCell<DLLToken>>,
-) -> (MatrixF32, Option<Rc<RefCell<DLLToken>>>, Vec<i32>) {
-    let mut seq = MatrixF32::new(seq_len, dim);
-    let mut seq_vals: Vec<f32> = vec![];
-    let mut target_token_ids: Vec<i32> = vec![];
-
-    let mut seq_dll_node = Some(Rc::clone(seq_dll_head));
-    let mut pos = 0i32;
-
-    while let Some(seq_dll_token) = seq_dll_node.clone()
-        && pos < seq_len
-    {
-        seq_vals.extend(seq_dll_token.borrow().embed.clone());
-        seq_dll_node = seq_dll_token.borrow().next.clone();
-        if let Some(dll_node) = seq_dll_node.clone() {
-            target_token_ids.push(dll_node.borrow().token.borrow().id);
-        }
-        pos += 1;
+    embedder: &Embedder,
+    tokens: &[u32],
+    pad_id: Option<u32>,
+) -> (Var, Vec<u32>) {
+    let input_len = tokens.len().min(seq_len as usize);
+    let mut vocab_ids: Vec<i32> = tokens[..input_len].iter().map(|id| *id as i32).collect();
+    let target_token_ids = tokens[1..tokens.len().min(input_len + 1)].to_vec();
+    if let Some(pad_id) = pad_id {
+        vocab_ids.resize(seq_len as usize, pad_id as i32);
     }
 
-    seq.vals = seq_vals;
-    (seq, seq_dll_node, target_token_ids)
+    let seq = embedder.embed(tape, &vocab_ids);
+    (seq, target_token_ids)
 }
 
-pub fn attention(dim: i32, seq: &MatrixF32) -> (AttentionParams, MatrixF32) {
-    let attention_params = AttentionParams::new(dim as usize);
-    let q = seq * &attention_params.w_q; // L * D
-    let mut k = seq * &attention_params.w_k; // L * D
-    let v = seq * &attention_params.w_v; // L * D
+pub fn attention(
+    tape: &mut Tape,
+    prefix: &str,
+    attention_params: &AttentionParams,
+    seq: Var,
+    cache: Option<&mut KVCache>,
+) -> Var {
+    let w_q = tape.param(&format!("{}.w_q", prefix), &attention_params.w_q);
+    let w_k = tape.param(&format!("{}.w_k", prefix), &attention_params.w_k);
+    let w_v = tape.param(&format!("{}.w_v", prefix), &attention_params.w_v);
+    let w_o = tape.param(&format!("{}.w_o", prefix), &attention_params.w_o);
+
+    let q = tape.matmul(seq, w_q); // L * D
+    let k = tape.matmul(seq, w_k); // L * D
+    let v = tape.matmul(seq, w_v); // L * D
+
+    // With a cache the new queries attend over every cached position too.
+    let (k, v) = match cache {
+        Some(cache) => {
+            cache.append(tape.value(k), tape.value(v));
+            let k = tape.constant(cache.keys.clone()); // T * D
+            let v = tape.constant(cache.values.clone()); // T * D
+            (k, v)
+        }
+        None => (k, v),
+    };
+
+    // Each head attends over its own dim / n_heads slice of Q, K and V.
+    let head_dim = attention_params.dim / attention_params.n_heads;
+    let mut heads: Vec<Var> = Vec::with_capacity(attention_params.n_heads as usize);
 
-    k.transpose(); // D * L
+    for head in 0..attention_params.n_heads {
+        let start = head * head_dim;
+        let q_h = tape.slice_cols(q, start, head_dim); // L * H
+        let k_h = tape.slice_cols(k, start, head_dim); // L * H
+        let v_h = tape.slice_cols(v, start, head_dim); // L * H
 
-    let mut scores = &q * &k; // L * L
-    scores = &scores / (dim as f32).sqrt();
-    scores.casual_mask();
-    scores.softmax_row();
-    let mut output = &scores * &v;
-    output = &output * &attention_params.w_o;
-    output = &output + seq;
+        let mut scores = tape.matmul_t(q_h, k_h); // L * L
+        scores = tape.div(scores, (head_dim as f32).sqrt());
+        scores = tape.casual_mask(scores);
+        scores = tape.softmax_row(scores);
+        heads.push(tape.matmul(scores, v_h)); // L * H
+    }
 
-    (attention_params, output)
+    let mut output = tape.concat_cols(&heads); // L * D
+    output = tape.matmul(output, w_o);
+    tape.add(output, seq)
 }
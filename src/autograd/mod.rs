@@ -0,0 +1,833 @@
+use std::collections::HashMap;
+
+use crate::utils::{MatrixF32, NNActivationE};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Var(usize);
+
+enum Op {
+    Leaf,
+    MatMul(Var, Var),
+    // a * b^T
+    MatMulT(Var, Var),
+    Add(Var, Var),
+    MulElem(Var, Var),
+    Div(Var, f32),
+    Transpose(Var),
+    SliceCols {
+        a: Var,
+        start: i32,
+    },
+    ConcatCols(Vec<Var>),
+    CasualMask(Var),
+    SoftmaxRow(Var),
+    LayerNorm {
+        x: Var,
+        gamma: Var,
+        beta: Var,
+        eps: f32,
+    },
+    AddBias(Var, Var),
+    Activation(Var, NNActivationE),
+    Embedding {
+        table: Var,
+        ids: Vec<i32>,
+    },
+    CrossEntropy {
+        probs: Var,
+        targets: Vec<i32>,
+    },
+}
+
+struct Node {
+    value: MatrixF32,
+    grad: Option<MatrixF32>,
+    op: Op,
+}
+
+// Named views of one parameter tensor. Names match the ones passed to
+// `Tape::param` so gradients can be looked up per parameter; vectors such as
+// layer-norm gains are reported as a single row.
+pub struct Param<'a> {
+    pub name: String,
+    pub rows: i32,
+    pub cols: i32,
+    pub vals: &'a [f32],
+}
+
+pub struct ParamMut<'a> {
+    pub name: String,
+    pub rows: i32,
+    pub cols: i32,
+    pub vals: &'a mut [f32],
+}
+
+impl<'a> Param<'a> {
+    pub fn matrix(name: String, matrix: &'a MatrixF32) -> Self {
+        Self {
+            name,
+            rows: matrix.rows,
+            cols: matrix.cols,
+            vals: &matrix.vals,
+        }
+    }
+
+    pub fn vector(name: String, vals: &'a [f32]) -> Self {
+        Self {
+            name,
+            rows: 1,
+            cols: vals.len() as i32,
+            vals,
+        }
+    }
+}
+
+impl<'a> ParamMut<'a> {
+    pub fn matrix(name: String, matrix: &'a mut MatrixF32) -> Self {
+        Self {
+            name,
+            rows: matrix.rows,
+            cols: matrix.cols,
+            vals: &mut matrix.vals,
+        }
+    }
+
+    pub fn vector(name: String, vals: &'a mut [f32]) -> Self {
+        Self {
+            name,
+            rows: 1,
+            cols: vals.len() as i32,
+            vals,
+        }
+    }
+}
+
+// Records every op of a forward pass so that `backward` can replay them in
+// reverse and accumulate a gradient for each node.
+//
+// Ops panic on operands of the wrong shape. Every shape follows from a
+// `ModelConfig` that has passed `validate`, and token ids and checkpoints are
+// checked before they reach the tape, so a mismatch here is a bug in the
+// model code rather than bad input.
+pub struct Tape {
+    nodes: Vec<Node>,
+    params: HashMap<String, Var>,
+}
+
+impl Default for Tape {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tape {
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![],
+            params: HashMap::new(),
+        }
+    }
+
+    fn push(&mut self, value: MatrixF32, op: Op) -> Var {
+        self.nodes.push(Node {
+            value,
+            grad: None,
+            op,
+        });
+        Var(self.nodes.len() - 1)
+    }
+
+    pub fn constant(&mut self, value: MatrixF32) -> Var {
+        self.push(value, Op::Leaf)
+    }
+
+    // Registers a trainable tensor under `name`; its gradient is reported by
+    // `param_grads` after `backward`. Registering the same name again reuses
+    // the existing leaf so repeated forward passes on one tape share weights.
+    pub fn param(&mut self, name: &str, value: &MatrixF32) -> Var {
+        if let Some(var) = self.params.get(name) {
+            return *var;
+        }
+
+        let var = self.push(value.clone(), Op::Leaf);
+        self.params.insert(name.to_string(), var);
+        var
+    }
+
+    pub fn param_vec(&mut self, name: &str, value: &[f32]) -> Var {
+        let row = MatrixF32 {
+            rows: 1,
+            cols: value.len() as i32,
+            vals: value.to_vec(),
+        };
+        self.param(name, &row)
+    }
+
+    pub fn value(&self, var: Var) -> &MatrixF32 {
+        &self.nodes[var.0].value
+    }
+
+    pub fn grad(&self, var: Var) -> Option<&MatrixF32> {
+        self.nodes[var.0].grad.as_ref()
+    }
+
+    pub fn param_grads(&self) -> HashMap<String, MatrixF32> {
+        self.params
+            .iter()
+            .map(|(name, var)| {
+                let grad = match self.grad(*var) {
+                    Some(grad) => grad.clone(),
+                    None => {
+                        let value = self.value(*var);
+                        MatrixF32::new(value.rows, value.cols)
+                    }
+                };
+                (name.clone(), grad)
+            })
+            .collect()
+    }
+
+    pub fn matmul(&mut self, a: Var, b: Var) -> Var {
+        let value = self.value(a) * self.value(b);
+        self.push(value, Op::MatMul(a, b))
+    }
+
+    // a * b^T, reading b through a transposed view instead of copying it.
+    pub fn matmul_t(&mut self, a: Var, b: Var) -> Var {
+        let value = self.value(a).view() * self.value(b).t();
+        self.push(value, Op::MatMulT(a, b))
+    }
+
+    pub fn add(&mut self, a: Var, b: Var) -> Var {
+        let value = self.value(a) + self.value(b);
+        self.push(value, Op::Add(a, b))
+    }
+
+    // Elementwise (Hadamard) product of two matrices of the same shape;
+    // panics on any other pair.
+    pub fn mul_elem(&mut self, a: Var, b: Var) -> Var {
+        let (a_val, b_val) = (self.value(a), self.value(b));
+        if a_val.rows != b_val.rows || a_val.cols != b_val.cols {
+            panic!("[mul_elem] dimensions don't match");
+        }
+
+        let mut value = a_val.clone();
+        for (val, b) in value.vals.iter_mut().zip(b_val.vals.iter()) {
+            *val *= b;
+        }
+        self.push(value, Op::MulElem(a, b))
+    }
+
+    // Panics if `rhs` is 0.
+    pub fn div(&mut self, a: Var, rhs: f32) -> Var {
+        let value = self.value(a) / rhs;
+        self.push(value, Op::Div(a, rhs))
+    }
+
+    pub fn transpose(&mut self, a: Var) -> Var {
+        let value = self.value(a).transposed();
+        self.push(value, Op::Transpose(a))
+    }
+
+    // Columns `start..start + len` of `a`.
+    pub fn slice_cols(&mut self, a: Var, start: i32, len: i32) -> Var {
+        let a_val = self.value(a);
+        let mut value = MatrixF32::new(a_val.rows, len);
+        for i in 0..a_val.rows {
+            for j in 0..len {
+                value[(i, j)] = a_val[(i, start + j)];
+            }
+        }
+        self.push(value, Op::SliceCols { a, start })
+    }
+
+    // Places the matrices in `parts` side by side; all must have equal rows,
+    // and a part that doesn't panics.
+    pub fn concat_cols(&mut self, parts: &[Var]) -> Var {
+        let rows = self.value(parts[0]).rows;
+        let cols = parts.iter().map(|part| self.value(*part).cols).sum();
+        let mut value = MatrixF32::new(rows, cols);
+
+        let mut offset = 0i32;
+        for part in parts {
+            let part_val = self.value(*part);
+            if part_val.rows != rows {
+                panic!("[concat_cols] row counts don't match");
+            }
+            for i in 0..rows {
+                for j in 0..part_val.cols {
+                    value[(i, offset + j)] = part_val[(i, j)];
+                }
+            }
+            offset += part_val.cols;
+        }
+
+        self.push(value, Op::ConcatCols(parts.to_vec()))
+    }
+
+    pub fn casual_mask(&mut self, a: Var) -> Var {
+        let mut value = self.value(a).clone();
+        value.casual_mask();
+        self.push(value, Op::CasualMask(a))
+    }
+
+    pub fn softmax_row(&mut self, a: Var) -> Var {
+        let mut value = self.value(a).clone();
+        value.softmax_row();
+        self.push(value, Op::SoftmaxRow(a))
+    }
+
+    pub fn layer_norm(&mut self, x: Var, eps: f32, gamma: Var, beta: Var) -> Var {
+        let value = self
+            .value(x)
+            .layer_norm(eps, &self.value(gamma).vals, &self.value(beta).vals);
+        self.push(
+            value,
+            Op::LayerNorm {
+                x,
+                gamma,
+                beta,
+                eps,
+            },
+        )
+    }
+
+    // Adds the 1 x cols `bias` row to every row of `x`.
+    pub fn add_bias(&mut self, x: Var, bias: Var) -> Var {
+        let mut value = self.value(x).clone();
+        let bias_vals = &self.value(bias).vals;
+        for i in 0..value.rows {
+            for j in 0..value.cols {
+                value[(i, j)] += bias_vals[j as usize];
+            }
+        }
+        self.push(value, Op::AddBias(x, bias))
+    }
+
+    pub fn activation(&mut self, x: Var, function: NNActivationE) -> Var {
+        let mut value = self.value(x).clone();
+        for val in value.vals.iter_mut() {
+            *val = function.apply(*val);
+        }
+        self.push(value, Op::Activation(x, function))
+    }
+
+    // Gathers row `ids[i]` of `table` into row `i` of the output.
+    pub fn embedding(&mut self, table: Var, ids: &[i32]) -> Var {
+        let table_val = self.value(table);
+        let mut value = MatrixF32::new(ids.len() as i32, table_val.cols);
+        for (i, id) in ids.iter().enumerate() {
+            for j in 0..table_val.cols {
+                value[(i as i32, j)] = table_val[(*id, j)];
+            }
+        }
+        self.push(
+            value,
+            Op::Embedding {
+                table,
+                ids: ids.to_vec(),
+            },
+        )
+    }
+
+    // Mean negative log-likelihood of `targets[i]` under row `i` of `probs`.
+    pub fn cross_entropy(&mut self, probs: Var, targets: &[i32]) -> Var {
+        let probs_val = self.value(probs);
+        let mut loss = 0f32;
+        for (i, target) in targets.iter().enumerate() {
+            loss += -probs_val[(i as i32, *target)].max(f32::MIN_POSITIVE).ln();
+        }
+
+        let mut value = MatrixF32::new(1, 1);
+        value.vals[0] = loss / targets.len() as f32;
+        self.push(
+            value,
+            Op::CrossEntropy {
+                probs,
+                targets: targets.to_vec(),
+            },
+        )
+    }
+
+    pub fn backward(&mut self, loss: Var) {
+        let loss_val = self.value(loss);
+        let mut seed = MatrixF32::new(loss_val.rows, loss_val.cols);
+        seed.vals.fill(1.0);
+        self.nodes[loss.0].grad = Some(seed);
+
+        for id in (0..=loss.0).rev() {
+            let Some(grad) = self.nodes[id].grad.take() else {
+                continue;
+            };
+            let op = std::mem::replace(&mut self.nodes[id].op, Op::Leaf);
+            self.propagate(id, &op, &grad);
+            self.nodes[id].op = op;
+            self.nodes[id].grad = Some(grad);
+        }
+    }
+
+    fn accumulate(&mut self, var: Var, delta: MatrixF32) {
+        let node = &mut self.nodes[var.0];
+        match &mut node.grad {
+            Some(grad) => {
+                for (g, d) in grad.vals.iter_mut().zip(delta.vals.iter()) {
+                    *g += d;
+                }
+            }
+            None => node.grad = Some(delta),
+        }
+    }
+
+    fn propagate(&mut self, id: usize, op: &Op, grad: &MatrixF32) {
+        match op {
+            Op::Leaf => {}
+            Op::MatMul(a, b) => {
+                let da = grad.view() * self.value(*b).t();
+                let db = self.value(*a).t() * grad.view();
+                self.accumulate(*a, da);
+                self.accumulate(*b, db);
+            }
+            Op::MatMulT(a, b) => {
+                let da = grad * self.value(*b);
+                let db = grad.t() * self.value(*a).view();
+                self.accumulate(*a, da);
+                self.accumulate(*b, db);
+            }
+            Op::Add(a, b) => {
+                self.accumulate(*a, grad.clone());
+                self.accumulate(*b, grad.clone());
+            }
+            Op::MulElem(a, b) => {
+                let mut da = grad.clone();
+                let mut db = grad.clone();
+                for (d, b) in da.vals.iter_mut().zip(self.value(*b).vals.iter()) {
+                    *d *= b;
+                }
+                for (d, a) in db.vals.iter_mut().zip(self.value(*a).vals.iter()) {
+                    *d *= a;
+                }
+                self.accumulate(*a, da);
+                self.accumulate(*b, db);
+            }
+            Op::Div(a, rhs) => {
+                self.accumulate(*a, grad / *rhs);
+            }
+            Op::Transpose(a) => {
+                self.accumulate(*a, grad.transposed());
+            }
+            Op::SliceCols { a, start } => {
+                let a_val = self.value(*a);
+                let mut da = MatrixF32::new(a_val.rows, a_val.cols);
+                for i in 0..grad.rows {
+                    for j in 0..grad.cols {
+                        da[(i, start + j)] = grad[(i, j)];
+                    }
+                }
+                self.accumulate(*a, da);
+            }
+            Op::ConcatCols(parts) => {
+                let mut offset = 0i32;
+                for part in parts {
+                    let part_cols = self.value(*part).cols;
+                    let mut dpart = MatrixF32::new(grad.rows, part_cols);
+                    for i in 0..grad.rows {
+                        for j in 0..part_cols {
+                            dpart[(i, j)] = grad[(i, offset + j)];
+                        }
+                    }
+                    self.accumulate(*part, dpart);
+                    offset += part_cols;
+                }
+            }
+            Op::CasualMask(a) => {
+                let mut da = grad.clone();
+                let offset = (da.cols - da.rows).max(0);
+                for i in 0..da.rows {
+                    for j in (i + 1 + offset)..da.cols {
+                        da[(i, j)] = 0.0;
+                    }
+                }
+                self.accumulate(*a, da);
+            }
+            Op::SoftmaxRow(a) => {
+                let y = &self.nodes[id].value;
+                let mut da = MatrixF32::new(y.rows, y.cols);
+                for i in 0..y.rows {
+                    let mut dot = 0f32;
+                    for j in 0..y.cols {
+                        dot += grad[(i, j)] * y[(i, j)];
+                    }
+                    for j in 0..y.cols {
+                        da[(i, j)] = y[(i, j)] * (grad[(i, j)] - dot);
+                    }
+                }
+                self.accumulate(*a, da);
+            }
+            Op::LayerNorm {
+                x,
+                gamma,
+                beta,
+                eps,
+            } => {
+                let x_val = self.value(*x);
+                let gamma_val = self.value(*gamma);
+                let (rows, cols) = (x_val.rows, x_val.cols);
+                let n = cols as f32;
+
+                let mut dx = MatrixF32::new(rows, cols);
+                let mut dgamma = MatrixF32::new(1, cols);
+                let mut dbeta = MatrixF32::new(1, cols);
+
+                for i in 0..rows {
+                    let mut mean = 0f32;
+                    for j in 0..cols {
+                        mean += x_val[(i, j)];
+                    }
+                    mean /= n;
+
+                    let mut variance = 0f32;
+                    for j in 0..cols {
+                        variance += (x_val[(i, j)] - mean).powi(2);
+                    }
+                    variance /= n;
+                    let stddev = (variance + eps).sqrt();
+
+                    let mut dxhat_sum = 0f32;
+                    let mut dxhat_xhat_sum = 0f32;
+                    for j in 0..cols {
+                        let xhat = (x_val[(i, j)] - mean) / stddev;
+                        let dxhat = grad[(i, j)] * gamma_val.vals[j as usize];
+                        dgamma.vals[j as usize] += grad[(i, j)] * xhat;
+                        dbeta.vals[j as usize] += grad[(i, j)];
+                        dxhat_sum += dxhat;
+                        dxhat_xhat_sum += dxhat * xhat;
+                    }
+
+                    for j in 0..cols {
+                        let xhat = (x_val[(i, j)] - mean) / stddev;
+                        let dxhat = grad[(i, j)] * gamma_val.vals[j as usize];
+                        dx[(i, j)] = (dxhat - dxhat_sum / n - xhat * dxhat_xhat_sum / n) / stddev;
+                    }
+                }
+
+                self.accumulate(*x, dx);
+                self.accumulate(*gamma, dgamma);
+                self.accumulate(*beta, dbeta);
+            }
+            Op::AddBias(x, bias) => {
+                let mut dbias = MatrixF32::new(1, grad.cols);
+                for i in 0..grad.rows {
+                    for j in 0..grad.cols {
+                        dbias.vals[j as usize] += grad[(i, j)];
+                    }
+                }
+                self.accumulate(*x, grad.clone());
+                self.accumulate(*bias, dbias);
+            }
+            Op::Activation(x, function) => {
+                let mut dx = grad.clone();
+                for (d, x) in dx.vals.iter_mut().zip(self.value(*x).vals.iter()) {
+                    *d *= function.derivative(*x);
+                }
+                self.accumulate(*x, dx);
+            }
+            Op::Embedding { table, ids } => {
+                let table_val = self.value(*table);
+                let mut dtable = MatrixF32::new(table_val.rows, table_val.cols);
+                for (i, id) in ids.iter().enumerate() {
+                    for j in 0..grad.cols {
+                        dtable[(*id, j)] += grad[(i as i32, j)];
+                    }
+                }
+                self.accumulate(*table, dtable);
+            }
+            Op::CrossEntropy { probs, targets } => {
+                let probs_val = self.value(*probs);
+                let mut dprobs = MatrixF32::new(probs_val.rows, probs_val.cols);
+                let n = targets.len() as f32;
+                for (i, target) in targets.iter().enumerate() {
+                    let p = probs_val[(i as i32, *target)].max(f32::MIN_POSITIVE);
+                    dprobs[(i as i32, *target)] = -grad.vals[0] / (n * p);
+                }
+                self.accumulate(*probs, dprobs);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::ModelConfig,
+        model::Model,
+        utils::{FeedForwardKindE, seed_rng},
+    };
+
+    // Step for the central differences; large enough that f32 rounding in
+    // the loss stays well below the tolerance.
+    const H: f32 = 1e-2;
+
+    fn assert_close(analytic: f32, numeric: f32, what: &str) {
+        let tolerance = 2e-3 + 3e-2 * analytic.abs().max(numeric.abs());
+        assert!(
+            (analytic - numeric).abs() <= tolerance,
+            "{}: backward gives {} but finite differences give {}",
+            what,
+            analytic,
+            numeric
+        );
+    }
+
+    fn random_matrix(rows: usize, cols: usize) -> MatrixF32 {
+        MatrixF32::new_rand_weight(rows, cols)
+    }
+
+    // Reduces `out` to a scalar with a different weight per entry, so every
+    // entry's gradient is distinct.
+    fn weighted_sum(tape: &mut Tape, out: Var) -> Var {
+        let (rows, cols) = (tape.value(out).rows, tape.value(out).cols);
+        let weights = MatrixF32 {
+            rows,
+            cols,
+            vals: (0..rows * cols)
+                .map(|i| ((i * 7 % 11) as f32 - 5.0) / 5.0)
+                .collect(),
+        };
+        let mut ones_left = MatrixF32::new(1, rows);
+        ones_left.vals.fill(1.0);
+        let mut ones_right = MatrixF32::new(cols, 1);
+        ones_right.vals.fill(1.0);
+
+        let weights = tape.constant(weights);
+        let ones_left = tape.constant(ones_left);
+        let ones_right = tape.constant(ones_right);
+        let weighted = tape.mul_elem(out, weights);
+        let row_sums = tape.matmul(ones_left, weighted);
+        tape.matmul(row_sums, ones_right)
+    }
+
+    // Compares the gradient `backward` gives every entry of every input with
+    // a central difference of the loss.
+    fn check_gradients(inputs: &[MatrixF32], build: impl Fn(&mut Tape, &[Var]) -> Var) {
+        let run = |inputs: &[MatrixF32]| {
+            let mut tape = Tape::new();
+            let vars: Vec<Var> = inputs
+                .iter()
+                .enumerate()
+                .map(|(i, input)| tape.param(&format!("input{}", i), input))
+                .collect();
+            let out = build(&mut tape, &vars);
+            let loss = weighted_sum(&mut tape, out);
+            (tape, vars, loss)
+        };
+
+        let (mut tape, vars, loss) = run(inputs);
+        tape.backward(loss);
+
+        for (i, var) in vars.iter().enumerate() {
+            let grad = tape.grad(*var).expect("every input feeds the loss");
+            for j in 0..inputs[i].vals.len() {
+                let mut shifted = inputs.to_vec();
+                shifted[i].vals[j] += H;
+                let (tape_plus, _, loss_plus) = run(&shifted);
+                shifted[i].vals[j] -= 2.0 * H;
+                let (tape_minus, _, loss_minus) = run(&shifted);
+
+                let numeric = (tape_plus.value(loss_plus).vals[0]
+                    - tape_minus.value(loss_minus).vals[0])
+                    / (2.0 * H);
+                assert_close(grad.vals[j], numeric, &format!("input {} entry {}", i, j));
+            }
+        }
+    }
+
+    #[test]
+    fn matmul_gradients() {
+        seed_rng(0);
+        check_gradients(&[random_matrix(3, 4), random_matrix(4, 5)], |tape, x| {
+            tape.matmul(x[0], x[1])
+        });
+    }
+
+    #[test]
+    fn matmul_t_gradients() {
+        seed_rng(1);
+        check_gradients(&[random_matrix(3, 4), random_matrix(5, 4)], |tape, x| {
+            tape.matmul_t(x[0], x[1])
+        });
+        // Both operands the same, as in a * a^T.
+        check_gradients(&[random_matrix(3, 4)], |tape, x| tape.matmul_t(x[0], x[0]));
+    }
+
+    #[test]
+    fn softmax_row_gradients() {
+        seed_rng(2);
+        check_gradients(&[random_matrix(3, 5)], |tape, x| tape.softmax_row(x[0]));
+    }
+
+    #[test]
+    fn masked_softmax_gradients() {
+        seed_rng(3);
+        check_gradients(&[random_matrix(3, 5)], |tape, x| {
+            let masked = tape.casual_mask(x[0]);
+            tape.softmax_row(masked)
+        });
+    }
+
+    #[test]
+    fn layer_norm_gradients() {
+        seed_rng(4);
+        let inputs = [
+            random_matrix(3, 6),
+            random_matrix(1, 6),
+            random_matrix(1, 6),
+        ];
+        check_gradients(&inputs, |tape, x| tape.layer_norm(x[0], 1e-5, x[1], x[2]));
+    }
+
+    #[test]
+    fn cross_entropy_gradients() {
+        seed_rng(5);
+        // Softmax keeps the probabilities valid while the logits move.
+        check_gradients(&[random_matrix(4, 6)], |tape, x| {
+            let probs = tape.softmax_row(x[0]);
+            tape.cross_entropy(probs, &[1, 5, 0, 1])
+        });
+
+        let probs = MatrixF32 {
+            rows: 2,
+            cols: 3,
+            vals: vec![0.2, 0.5, 0.3, 0.6, 0.1, 0.3],
+        };
+        check_gradients(&[probs], |tape, x| tape.cross_entropy(x[0], &[1, 2]));
+    }
+
+    #[test]
+    fn embedding_gradients() {
+        seed_rng(6);
+        // Ids repeat, so their rows' gradients have to add up.
+        check_gradients(&[random_matrix(5, 4)], |tape, x| {
+            tape.embedding(x[0], &[2, 0, 2, 4, 2])
+        });
+    }
+
+    #[test]
+    fn activation_gradients() {
+        seed_rng(7);
+        let mut x = random_matrix(3, 4);
+        // Keep clear of ReLU's kink at 0.
+        for val in x.vals.iter_mut() {
+            *val += 0.1f32.copysign(*val);
+        }
+
+        for function in [
+            NNActivationE::NNActivationLinear,
+            NNActivationE::NNActivationRELU,
+            NNActivationE::NNActivationGELU,
+            NNActivationE::NNActivationATAN,
+            NNActivationE::NNActivationSigmoid,
+            NNActivationE::NNActivationSiLU,
+        ] {
+            check_gradients(std::slice::from_ref(&x), |tape, x| {
+                tape.activation(x[0], function)
+            });
+        }
+    }
+
+    #[test]
+    fn slice_and_concat_cols_gradients() {
+        seed_rng(8);
+        check_gradients(&[random_matrix(3, 6)], |tape, x| {
+            tape.slice_cols(x[0], 2, 3)
+        });
+        // `x[0]` appears twice, so its two gradients have to add up.
+        check_gradients(&[random_matrix(3, 2), random_matrix(3, 4)], |tape, x| {
+            tape.concat_cols(&[x[0], x[1], x[0]])
+        });
+    }
+
+    #[test]
+    fn mul_elem_gradients() {
+        seed_rng(9);
+        check_gradients(&[random_matrix(3, 4), random_matrix(3, 4)], |tape, x| {
+            tape.mul_elem(x[0], x[1])
+        });
+        check_gradients(&[random_matrix(3, 4)], |tape, x| tape.mul_elem(x[0], x[0]));
+    }
+
+    #[test]
+    fn elementwise_gradients() {
+        seed_rng(10);
+        let inputs = [
+            random_matrix(3, 4),
+            random_matrix(3, 4),
+            random_matrix(1, 4),
+        ];
+        check_gradients(&inputs, |tape, x| {
+            let sum = tape.add(x[0], x[1]);
+            let biased = tape.add_bias(sum, x[2]);
+            let scaled = tape.div(biased, 3.0);
+            tape.transpose(scaled)
+        });
+    }
+
+    // Finite differences through a whole model, for eight evenly spaced
+    // entries of every parameter.
+    fn check_model_gradients(feed_forward: FeedForwardKindE) {
+        seed_rng(11);
+        let mut model = Model::new(&ModelConfig {
+            seq_len: 6,
+            dim: 8,
+            vocab_size: 12,
+            num_transformers: 2,
+            n_heads: 2,
+            hidden_nodes: 16,
+            feed_forward,
+            ..ModelConfig::default()
+        });
+        let tokens: Vec<u32> = vec![3, 1, 4, 1, 5, 9, 2];
+        let loss_of = |model: &Model| {
+            let mut tape = Tape::new();
+            let (probs, targets) = model.forward(&mut tape, &tokens);
+            let loss = model.cross_entropy(&mut tape, probs, targets);
+            (tape, loss)
+        };
+
+        let (mut tape, loss) = loss_of(&model);
+        tape.backward(loss);
+        let grads = tape.param_grads();
+
+        let num_params = model.params().len();
+        for p in 0..num_params {
+            let (name, len) = {
+                let params = model.params();
+                (params[p].name.clone(), params[p].vals.len())
+            };
+            let grad = &grads[&name];
+            for j in (0..len).step_by(len.div_ceil(8)) {
+                let original = model.params_mut()[p].vals[j];
+                model.params_mut()[p].vals[j] = original + H;
+                let (tape_plus, loss_plus) = loss_of(&model);
+                model.params_mut()[p].vals[j] = original - H;
+                let (tape_minus, loss_minus) = loss_of(&model);
+                model.params_mut()[p].vals[j] = original;
+
+                let numeric = (tape_plus.value(loss_plus).vals[0]
+                    - tape_minus.value(loss_minus).vals[0])
+                    / (2.0 * H);
+                assert_close(grad.vals[j], numeric, &format!("{} entry {}", name, j));
+            }
+        }
+    }
+
+    #[test]
+    fn model_gradients() {
+        check_model_gradients(FeedForwardKindE::FeedForwardMLP {
+            activation: NNActivationE::NNActivationGELU,
+        });
+        check_model_gradients(FeedForwardKindE::FeedForwardGLU {
+            activation: NNActivationE::NNActivationSiLU,
+        });
+    }
+}
@@ -0,0 +1,469 @@
+use std::{collections::HashMap, fs};
+
+use crate::{
+    config::ModelConfig,
+    error::ErrorE,
+    json::parse_json,
+    model::Model,
+    optimizer::{Optimizer, OptimizerE},
+    train::TrainPosition,
+};
+
+const CHECKPOINT_MAGIC: &[u8; 8] = b"TGPTCKPT";
+const CHECKPOINT_VERSION: u32 = 6;
+
+// Layout (all numbers little-endian):
+//   magic, version
+//   model config as a JSON string
+//   tensor count, then per tensor: name, rows, cols, rows * cols f32 values
+//   optimizer flag, then if set: kind tag and hyper-parameters, lr,
+//   step_count, first moments, second moments
+//   training position flag, then if set: epoch, window, seed
+pub fn save_checkpoint(
+    filename: &str,
+    model: &Model,
+    optimizer: Option<&Optimizer>,
+    position: Option<&TrainPosition>,
+) -> Result<(), ErrorE> {
+    let mut bytes: Vec<u8> = vec![];
+    bytes.extend_from_slice(CHECKPOINT_MAGIC);
+    write_u32(&mut bytes, CHECKPOINT_VERSION);
+
+    write_str(&mut bytes, &model.config.to_json().to_json_string());
+
+    let params = model.params();
+    write_u32(&mut bytes, params.len() as u32);
+    for param in params.iter() {
+        write_str(&mut bytes, &param.name);
+        write_i32(&mut bytes, param.rows);
+        write_i32(&mut bytes, param.cols);
+        write_f32s(&mut bytes, param.vals);
+    }
+
+    match optimizer {
+        Some(optimizer) => {
+            bytes.push(1);
+            write_optimizer(&mut bytes, optimizer);
+        }
+        None => bytes.push(0),
+    }
+
+    match position {
+        Some(position) => {
+            bytes.push(1);
+            write_i32(&mut bytes, position.epoch);
+            write_u64(&mut bytes, position.window as u64);
+            write_u64(&mut bytes, position.seed);
+        }
+        None => bytes.push(0),
+    }
+
+    fs::write(filename, bytes).map_err(|error| ErrorE::io(filename, error))
+}
+
+// The training position is None for checkpoints saved without one.
+pub fn load_checkpoint(
+    filename: &str,
+) -> Result<(Model, Option<Optimizer>, Option<TrainPosition>), ErrorE> {
+    let bytes = fs::read(filename).map_err(|error| ErrorE::io(filename, error))?;
+    let mut reader = ByteReader {
+        bytes: &bytes,
+        pos: 0,
+    };
+
+    if reader.take(CHECKPOINT_MAGIC.len())? != CHECKPOINT_MAGIC {
+        return Err(ErrorE::ErrorCheckpointFormat(format!(
+            "{} is not a checkpoint",
+            filename
+        )));
+    }
+    let version = reader.read_u32()?;
+    if version != CHECKPOINT_VERSION {
+        return Err(ErrorE::ErrorCheckpointFormat(format!(
+            "Unsupported checkpoint version {}",
+            version
+        )));
+    }
+
+    let config = ModelConfig::from_json(&parse_json(&reader.read_str()?)?)?;
+    // A corrupt config could otherwise ask for far more memory than the
+    // weights in the file could ever fill.
+    config.validate()?;
+    let num_params = config.num_params().unwrap_or(usize::MAX);
+    if num_params > reader.remaining() / 4 {
+        return Err(ErrorE::ErrorCheckpointFormat(format!(
+            "Checkpoint config needs {} weights but the file is too short to hold them",
+            num_params
+        )));
+    }
+    let mut model = Model::try_new(&config)?;
+
+    let num_tensors = reader.read_u32()? as usize;
+    let mut tensors: HashMap<String, (i32, i32, Vec<f32>)> = HashMap::new();
+    for _ in 0..num_tensors {
+        let name = reader.read_str()?;
+        let rows = reader.read_i32()?;
+        let cols = reader.read_i32()?;
+        let len = usize::try_from(rows)
+            .ok()
+            .zip(usize::try_from(cols).ok())
+            .and_then(|(rows, cols)| rows.checked_mul(cols))
+            .ok_or_else(|| {
+                ErrorE::ErrorCheckpointFormat(format!(
+                    "Tensor {} has a bad shape {}x{}",
+                    name, rows, cols
+                ))
+            })?;
+        let vals = reader.read_f32s(len)?;
+        tensors.insert(name, (rows, cols, vals));
+    }
+
+    for param in model.params_mut() {
+        let Some((rows, cols, vals)) = tensors.remove(&param.name) else {
+            return Err(ErrorE::ErrorCheckpointFormat(format!(
+                "Checkpoint is missing tensor {}",
+                param.name
+            )));
+        };
+        if rows != param.rows || cols != param.cols {
+            return Err(ErrorE::ErrorCheckpointFormat(format!(
+                "Tensor {} is {}x{} in the checkpoint but {}x{} in the model",
+                param.name, rows, cols, param.rows, param.cols
+            )));
+        }
+        param.vals.copy_from_slice(&vals);
+    }
+
+    let optimizer = match reader.read_u8()? {
+        0 => None,
+        _ => Some(read_optimizer(&mut reader)?),
+    };
+    if let Some(optimizer) = &optimizer {
+        check_moments(&model, optimizer)?;
+    }
+
+    let position = match reader.read_u8()? {
+        0 => None,
+        _ => Some(TrainPosition {
+            epoch: reader.read_i32()?,
+            window: usize::try_from(reader.read_u64()?).map_err(|_| {
+                ErrorE::ErrorCheckpointFormat("Checkpoint has a bad window".to_string())
+            })?,
+            seed: reader.read_u64()?,
+        }),
+    };
+
+    Ok((model, optimizer, position))
+}
+
+fn write_optimizer(bytes: &mut Vec<u8>, optimizer: &Optimizer) {
+    match optimizer.kind {
+        OptimizerE::OptimizerSGD { momentum } => {
+            bytes.push(0);
+            write_f32(bytes, momentum);
+        }
+        OptimizerE::OptimizerAdam {
+            beta1,
+            beta2,
+            eps,
+            weight_decay,
+        } => {
+            bytes.push(1);
+            write_f32s(bytes, &[beta1, beta2, eps, weight_decay]);
+        }
+        OptimizerE::OptimizerAdamW {
+            beta1,
+            beta2,
+            eps,
+            weight_decay,
+        } => {
+            bytes.push(2);
+            write_f32s(bytes, &[beta1, beta2, eps, weight_decay]);
+        }
+    }
+
+    write_f32(bytes, optimizer.lr);
+    write_i32(bytes, optimizer.step_count);
+    write_moments(bytes, &optimizer.first_moments);
+    write_moments(bytes, &optimizer.second_moments);
+}
+
+fn read_optimizer(reader: &mut ByteReader) -> Result<Optimizer, ErrorE> {
+    let kind = match reader.read_u8()? {
+        0 => OptimizerE::OptimizerSGD {
+            momentum: reader.read_f32()?,
+        },
+        tag @ (1 | 2) => {
+            let vals = reader.read_f32s(4)?;
+            if tag == 1 {
+                OptimizerE::OptimizerAdam {
+                    beta1: vals[0],
+                    beta2: vals[1],
+                    eps: vals[2],
+                    weight_decay: vals[3],
+                }
+            } else {
+                OptimizerE::OptimizerAdamW {
+                    beta1: vals[0],
+                    beta2: vals[1],
+                    eps: vals[2],
+                    weight_decay: vals[3],
+                }
+            }
+        }
+        tag => {
+            return Err(ErrorE::ErrorCheckpointFormat(format!(
+                "Unknown optimizer kind {}",
+                tag
+            )));
+        }
+    };
+
+    let mut optimizer = Optimizer::new(kind, reader.read_f32()?);
+    optimizer.step_count = reader.read_i32()?;
+    optimizer.first_moments = read_moments(reader)?;
+    optimizer.second_moments = read_moments(reader)?;
+    Ok(optimizer)
+}
+
+// Every moment has to belong to a model parameter and be the same length,
+// or the next optimizer step would index past it.
+fn check_moments(model: &Model, optimizer: &Optimizer) -> Result<(), ErrorE> {
+    let params = model.params();
+    for moments in [&optimizer.first_moments, &optimizer.second_moments] {
+        for (name, vals) in moments.iter() {
+            let matches = params
+                .iter()
+                .any(|param| param.name == *name && param.vals.len() == vals.len());
+            if !matches {
+                return Err(ErrorE::ErrorCheckpointFormat(format!(
+                    "Checkpoint has optimizer state for {} that doesn't match the model",
+                    name
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_moments(bytes: &mut Vec<u8>, moments: &HashMap<String, Vec<f32>>) {
+    let mut names: Vec<&String> = moments.keys().collect();
+    names.sort();
+
+    write_u32(bytes, names.len() as u32);
+    for name in names {
+        let vals = &moments[name];
+        write_str(bytes, name);
+        write_u32(bytes, vals.len() as u32);
+        write_f32s(bytes, vals);
+    }
+}
+
+fn read_moments(reader: &mut ByteReader) -> Result<HashMap<String, Vec<f32>>, ErrorE> {
+    let count = reader.read_u32()? as usize;
+    let mut moments: HashMap<String, Vec<f32>> = HashMap::new();
+    for _ in 0..count {
+        let name = reader.read_str()?;
+        let len = reader.read_u32()? as usize;
+        moments.insert(name, reader.read_f32s(len)?);
+    }
+    Ok(moments)
+}
+
+fn write_u32(bytes: &mut Vec<u8>, val: u32) {
+    bytes.extend_from_slice(&val.to_le_bytes());
+}
+
+fn write_u64(bytes: &mut Vec<u8>, val: u64) {
+    bytes.extend_from_slice(&val.to_le_bytes());
+}
+
+fn write_i32(bytes: &mut Vec<u8>, val: i32) {
+    bytes.extend_from_slice(&val.to_le_bytes());
+}
+
+fn write_f32(bytes: &mut Vec<u8>, val: f32) {
+    bytes.extend_from_slice(&val.to_le_bytes());
+}
+
+fn write_f32s(bytes: &mut Vec<u8>, vals: &[f32]) {
+    for val in vals {
+        write_f32(bytes, *val);
+    }
+}
+
+fn write_str(bytes: &mut Vec<u8>, val: &str) {
+    write_u32(bytes, val.len() as u32);
+    bytes.extend_from_slice(val.as_bytes());
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ErrorE> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| {
+                ErrorE::ErrorCheckpointFormat("Checkpoint ended unexpectedly".to_string())
+            })?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, ErrorE> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, ErrorE> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, ErrorE> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn read_i32(&mut self) -> Result<i32, ErrorE> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_f32(&mut self) -> Result<f32, ErrorE> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_f32s(&mut self, len: usize) -> Result<Vec<f32>, ErrorE> {
+        let num_bytes = len.checked_mul(4).ok_or_else(|| {
+            ErrorE::ErrorCheckpointFormat("Checkpoint ended unexpectedly".to_string())
+        })?;
+        Ok(self
+            .take(num_bytes)?
+            .chunks_exact(4)
+            .map(|val| f32::from_le_bytes(val.try_into().unwrap()))
+            .collect())
+    }
+
+    fn read_str(&mut self) -> Result<String, ErrorE> {
+        let len = self.read_u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| {
+            ErrorE::ErrorCheckpointFormat("Checkpoint has a malformed name".to_string())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{temp_path, test_model};
+
+    #[test]
+    fn save_then_load_restores_weights_optimizer_and_position() {
+        let mut model = test_model(0);
+        model.config.pad_id = Some(3);
+        let mut optimizer = Optimizer::adamw(0.02, 0.1);
+        optimizer.step_count = 7;
+        optimizer.first_moments.insert(
+            "w_o".to_string(),
+            (0..160).map(|i| i as f32 / 7.0 - 9.0).collect(),
+        );
+        optimizer.second_moments.insert(
+            "w_o".to_string(),
+            (0..160).map(|i| i as f32 * 1e-9).collect(),
+        );
+        optimizer
+            .first_moments
+            .insert("gamma".to_string(), vec![3.0; 8]);
+        let position = TrainPosition {
+            epoch: 2,
+            window: 5,
+            seed: u64::MAX,
+        };
+
+        let path = temp_path("roundtrip.ckpt");
+        save_checkpoint(&path, &model, Some(&optimizer), Some(&position)).unwrap();
+        let loaded = load_checkpoint(&path);
+        fs::remove_file(&path).unwrap();
+        let (loaded, loaded_optimizer, loaded_position) = loaded.unwrap();
+
+        assert_eq!(loaded.config, model.config);
+        for (loaded, saved) in loaded.params().iter().zip(model.params().iter()) {
+            assert_eq!(loaded.name, saved.name);
+            assert_eq!(loaded.vals, saved.vals, "{}", saved.name);
+        }
+        assert_eq!(loaded_position, Some(position));
+
+        let loaded_optimizer = loaded_optimizer.unwrap();
+        assert!(matches!(
+            loaded_optimizer.kind,
+            OptimizerE::OptimizerAdamW { weight_decay, .. } if weight_decay == 0.1
+        ));
+        assert_eq!(loaded_optimizer.lr, 0.02);
+        assert_eq!(loaded_optimizer.step_count, 7);
+        assert_eq!(loaded_optimizer.first_moments, optimizer.first_moments);
+        assert_eq!(loaded_optimizer.second_moments, optimizer.second_moments);
+    }
+
+    #[test]
+    fn save_then_load_without_optimizer_or_position() {
+        let model = test_model(1);
+        let path = temp_path("weights-only.ckpt");
+        save_checkpoint(&path, &model, None, None).unwrap();
+        let loaded = load_checkpoint(&path);
+        fs::remove_file(&path).unwrap();
+        let (loaded, optimizer, position) = loaded.unwrap();
+
+        assert!(optimizer.is_none());
+        assert!(position.is_none());
+        assert_eq!(loaded.w_o.vals, model.w_o.vals);
+    }
+
+    #[test]
+    fn moments_that_dont_match_the_model_are_rejected() {
+        let model = test_model(0);
+        let bad_moments = [("w_o", 2), ("gamma", 9), ("no_such_param", 8)];
+        for (name, len) in bad_moments {
+            let mut optimizer = Optimizer::momentum(0.1, 0.9);
+            optimizer
+                .first_moments
+                .insert(name.to_string(), vec![0.0; len]);
+
+            let path = temp_path(&format!("bad-moments-{}.ckpt", name));
+            save_checkpoint(&path, &model, Some(&optimizer), None).unwrap();
+            let result = load_checkpoint(&path);
+            fs::remove_file(&path).unwrap();
+            assert!(
+                matches!(result, Err(ErrorE::ErrorCheckpointFormat(_))),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_config_is_rejected_before_allocating() {
+        let config = ModelConfig {
+            dim: 65536,
+            vocab_size: 65536,
+            ..ModelConfig::default()
+        };
+        let mut bytes: Vec<u8> = vec![];
+        bytes.extend_from_slice(CHECKPOINT_MAGIC);
+        write_u32(&mut bytes, CHECKPOINT_VERSION);
+        write_str(&mut bytes, &config.to_json().to_json_string());
+        write_u32(&mut bytes, 0);
+
+        let path = temp_path("oversized.ckpt");
+        fs::write(&path, bytes).unwrap();
+        let result = load_checkpoint(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(ErrorE::ErrorCheckpointFormat(_))));
+    }
+}
@@ -0,0 +1,751 @@
+use std::{fs, path::Path};
+
+use crate::{
+    checkpoint::{load_checkpoint, save_checkpoint},
+    config::ModelConfig,
+    error::ErrorE,
+    generate::{Sampler, SamplingE, generate},
+    model::Model,
+    optimizer::Optimizer,
+    parallel::set_num_threads,
+    pretokenizer::PreTokenizerE,
+    safetensors::{SafeTensorsFile, load_safetensors, read_safetensors, save_safetensors},
+    tokenizer::{self, BpeTrainerConfig, Tokenizer},
+    train::{TrainConfig, evaluate, train},
+    utils::{read_file, seed_rng},
+};
+
+pub const USAGE: &str = "\
+Usage: tinygpt <command> [options]
+
+Commands:
+  train <corpus>       Train a model on a text file, resuming from the
+                       checkpoint in the output directory if there is one
+  generate [prompt]    Sample text from a trained model
+  tokenize <corpus>    Train (or load) a tokenizer and report how it splits a file
+  eval <corpus>        Report the model's loss and perplexity on a text file
+  info <file>          Describe a checkpoint, safetensors or tokenizer file
+  help                 Show this message
+
+Model options:
+  -c, --config <path>      Model config (.toml or .json)
+  -s, --set <key=value>    Override a config value, e.g. --set dim=16 (repeatable)
+
+File options:
+  -o, --out-dir <dir>      Where outputs are written and looked up [default: out]
+      --tokenizer <path>   Tokenizer file [default: <out-dir>/tokenizer.txt]
+      --checkpoint <path>  Checkpoint file [default: <out-dir>/model.ckpt]
+      --weights <path>     Start from (or generate with) a safetensors file
+
+Tokenizer options (when a new tokenizer is trained, its vocab grows to the
+config's vocab_size, or 512):
+      --min-pair-freq <n>  Don't merge pairs seen fewer times [default: 2]
+      --max-token-len <n>  Longest token in bytes, 0 = no limit [default: 16]
+      --pre-tokenizer <name>
+                           gpt2 (merge within words) or none [default: gpt2]
+      --special-token <text>
+                           Register a special token after the builtin <|bos|>,
+                           <|eos|>, <|pad|> and <|unk|> (repeatable)
+      --parse-special      Read special token text in the corpus or prompt as
+                           the special token rather than as plain text (kept
+                           in the saved tokenizer)
+
+Training options:
+      --optimizer <name>   sgd, momentum, adam or adamw [default: adamw]
+      --lr <float>         Learning rate [default: 0.01]
+      --epochs <n>         Total epochs, counting those a resumed checkpoint
+                           already finished [default: 3]
+      --batch-size <n>     [default: 8]
+      --save-every <n>     Also write the checkpoint every n steps, so an
+                           interrupted run can resume, 0 = only at the end
+                           [default: 100]
+      --random-windows     Sample training windows at random offsets
+      --seed <n>           Seed weight init, window order and sampling
+
+Generation options:
+  -p, --prompt <text>      [default: \"The \"]
+  -n, --max-tokens <n>     [default: 64]
+      --sampler <name>     greedy, temperature, top_k or top_p [default: top_p]
+      --temperature <f>    [default: 0.8]
+      --top-k <n>          [default: 10]
+      --top-p <f>          [default: 0.9]
+
+Output options:
+  -v, --verbose            Log every training step and print token ids
+  -q, --quiet              Only print results and errors
+      --threads <n>        Threads for matrix kernels, 0 = all cores [default: 0]
+  -h, --help               Show this message
+";
+
+pub struct CliOptions {
+    pub command: String,
+    pub positional: Vec<String>,
+    pub config: Option<String>,
+    pub overrides: Vec<(String, String)>,
+    pub out_dir: String,
+    pub tokenizer: Option<String>,
+    pub checkpoint: Option<String>,
+    pub weights: Option<String>,
+    pub min_pair_freq: usize,
+    pub max_token_len: usize,
+    pub pre_tokenizer: String,
+    pub special_tokens: Vec<String>,
+    pub parse_special: bool,
+    // None means adamw with a learning rate of 0.01. Kept apart from the
+    // defaults so resuming can tell whether they were passed.
+    pub optimizer: Option<String>,
+    pub lr: Option<f32>,
+    pub epochs: i32,
+    pub batch_size: i32,
+    // Steps between checkpoints written during training; 0 only saves at
+    // the end.
+    pub save_every: i32,
+    pub random_windows: bool,
+    pub seed: Option<u64>,
+    // 0 uses every core; 1 keeps all work on the main thread.
+    pub threads: usize,
+    pub prompt: Option<String>,
+    pub max_tokens: i32,
+    pub sampler: String,
+    pub temperature: f32,
+    pub top_k: usize,
+    pub top_p: f32,
+    // 0 quiet, 1 normal, 2 verbose.
+    pub verbosity: i32,
+    pub help: bool,
+}
+
+impl Default for CliOptions {
+    fn default() -> Self {
+        Self {
+            command: String::new(),
+            positional: vec![],
+            config: None,
+            overrides: vec![],
+            out_dir: "out".to_string(),
+            tokenizer: None,
+            checkpoint: None,
+            weights: None,
+            min_pair_freq: BpeTrainerConfig::default().min_pair_frequency,
+            max_token_len: BpeTrainerConfig::default().max_token_len,
+            pre_tokenizer: BpeTrainerConfig::default().pre_tokenizer.name().to_string(),
+            special_tokens: vec![],
+            parse_special: false,
+            optimizer: None,
+            lr: None,
+            epochs: 3,
+            batch_size: 8,
+            save_every: 100,
+            random_windows: false,
+            seed: None,
+            threads: 0,
+            prompt: None,
+            max_tokens: 64,
+            sampler: "top_p".to_string(),
+            temperature: 0.8,
+            top_k: 10,
+            top_p: 0.9,
+            verbosity: 1,
+            help: false,
+        }
+    }
+}
+
+fn usage_error(message: String) -> ErrorE {
+    ErrorE::ErrorUsage(message)
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, ErrorE> {
+    value
+        .parse::<T>()
+        .map_err(|_| usage_error(format!("Invalid value {:?} for {}", value, flag)))
+}
+
+// `args` excludes the program name. Flags take their value either as the
+// next argument or after `=`.
+pub fn parse_args(args: &[String]) -> Result<CliOptions, ErrorE> {
+    let mut options = CliOptions::default();
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        if !arg.starts_with('-') || arg == "-" {
+            if options.command.is_empty() {
+                options.command = arg.clone();
+            } else {
+                options.positional.push(arg.clone());
+            }
+            continue;
+        }
+
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+
+        match flag {
+            "-h" | "--help" => options.help = true,
+            "-v" | "--verbose" => options.verbosity = 2,
+            "-q" | "--quiet" => options.verbosity = 0,
+            "--random-windows" => options.random_windows = true,
+            "--parse-special" => options.parse_special = true,
+            _ => {
+                let value = match inline_value {
+                    Some(value) => value,
+                    None => iter
+                        .next()
+                        .cloned()
+                        .ok_or_else(|| usage_error(format!("{} needs a value", flag)))?,
+                };
+
+                match flag {
+                    "-c" | "--config" => options.config = Some(value),
+                    "-s" | "--set" => {
+                        let Some((key, val)) = value.split_once('=') else {
+                            return Err(usage_error(format!(
+                                "--set expects key=value, got {:?}",
+                                value
+                            )));
+                        };
+                        options
+                            .overrides
+                            .push((key.trim().to_string(), val.trim().to_string()));
+                    }
+                    "-o" | "--out-dir" => options.out_dir = value,
+                    "--tokenizer" => options.tokenizer = Some(value),
+                    "--checkpoint" => options.checkpoint = Some(value),
+                    "--weights" => options.weights = Some(value),
+                    "--min-pair-freq" => options.min_pair_freq = parse_value(flag, &value)?,
+                    "--max-token-len" => options.max_token_len = parse_value(flag, &value)?,
+                    "--pre-tokenizer" => options.pre_tokenizer = value,
+                    "--special-token" => options.special_tokens.push(value),
+                    "--optimizer" => options.optimizer = Some(value),
+                    "--lr" => options.lr = Some(parse_value(flag, &value)?),
+                    "--epochs" => options.epochs = parse_value(flag, &value)?,
+                    "--batch-size" => options.batch_size = parse_value(flag, &value)?,
+                    "--save-every" => options.save_every = parse_value(flag, &value)?,
+                    "--seed" => options.seed = Some(parse_value(flag, &value)?),
+                    "--threads" => options.threads = parse_value(flag, &value)?,
+                    "-p" | "--prompt" => options.prompt = Some(value),
+                    "-n" | "--max-tokens" => options.max_tokens = parse_value(flag, &value)?,
+                    "--sampler" => options.sampler = value,
+                    "--temperature" => options.temperature = parse_value(flag, &value)?,
+                    "--top-k" => options.top_k = parse_value(flag, &value)?,
+                    "--top-p" => options.top_p = parse_value(flag, &value)?,
+                    _ => return Err(usage_error(format!("Unknown option {}", flag))),
+                }
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+pub fn run(args: &[String]) -> Result<(), ErrorE> {
+    let options = parse_args(args)?;
+
+    if options.help || options.command == "help" {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+    if let Some(seed) = options.seed {
+        seed_rng(seed);
+    }
+    set_num_threads(options.threads);
+
+    match options.command.as_str() {
+        "train" => run_train(&options),
+        "generate" => run_generate(&options),
+        "tokenize" => run_tokenize(&options),
+        "eval" => run_eval(&options),
+        "info" => run_info(&options),
+        "" => Err(usage_error("No command given".to_string())),
+        command => Err(usage_error(format!("Unknown command {}", command))),
+    }
+}
+
+impl CliOptions {
+    fn log(self: &CliOptions, message: String) {
+        if self.verbosity >= 1 {
+            println!("{}", message);
+        }
+    }
+
+    // The single positional argument a command like `train <corpus>` needs.
+    fn input(self: &CliOptions, what: &str) -> Result<&str, ErrorE> {
+        match self.positional.as_slice() {
+            [input] => Ok(input),
+            [] => Err(usage_error(format!(
+                "`tinygpt {}` needs a {}",
+                self.command, what
+            ))),
+            _ => Err(usage_error(format!(
+                "`tinygpt {}` takes a single {}",
+                self.command, what
+            ))),
+        }
+    }
+
+    fn out_path(self: &CliOptions, name: &str) -> String {
+        Path::new(&self.out_dir)
+            .join(name)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn tokenizer_path(self: &CliOptions) -> String {
+        self.tokenizer
+            .clone()
+            .unwrap_or_else(|| self.out_path("tokenizer.txt"))
+    }
+
+    fn checkpoint_path(self: &CliOptions) -> String {
+        self.checkpoint
+            .clone()
+            .unwrap_or_else(|| self.out_path("model.ckpt"))
+    }
+
+    fn ensure_out_dir(self: &CliOptions) -> Result<(), ErrorE> {
+        fs::create_dir_all(&self.out_dir).map_err(|error| ErrorE::io(&self.out_dir, error))
+    }
+
+    // Config file first, then `--set` overrides.
+    fn base_model_config(self: &CliOptions) -> Result<ModelConfig, ErrorE> {
+        let mut config = match &self.config {
+            Some(config_location) => ModelConfig::load(config_location)?,
+            None => ModelConfig::default(),
+        };
+        for (key, value) in self.overrides.iter() {
+            config.set(key, value)?;
+        }
+        Ok(config)
+    }
+
+    // The base config with the tokenizer's vocab and padding filled in.
+    fn model_config(self: &CliOptions, tokenizer: &Tokenizer) -> Result<ModelConfig, ErrorE> {
+        let mut config = self.base_model_config()?;
+        if config.pad_id.is_none() {
+            config.pad_id = tokenizer.pad_id();
+        }
+
+        let vocab_size = tokenizer.vocab_size() as i32;
+        if config.vocab_size == 0 {
+            config.vocab_size = vocab_size;
+        } else if config.vocab_size != vocab_size {
+            return Err(ErrorE::ErrorConfig(format!(
+                "The config's vocab_size {} doesn't match the tokenizer's {}",
+                config.vocab_size, vocab_size
+            )));
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn new_optimizer(self: &CliOptions) -> Result<Optimizer, ErrorE> {
+        let lr = self.lr.unwrap_or(0.01);
+        match self.optimizer.as_deref().unwrap_or("adamw") {
+            "sgd" => Ok(Optimizer::sgd(lr)),
+            "momentum" => Ok(Optimizer::momentum(lr, 0.9)),
+            "adam" => Ok(Optimizer::adam(lr)),
+            "adamw" => Ok(Optimizer::adamw(lr, 0.01)),
+            name => Err(usage_error(format!("Unknown optimizer {}", name))),
+        }
+    }
+
+    fn sampler(self: &CliOptions) -> Result<Sampler, ErrorE> {
+        let strategy = match self.sampler.as_str() {
+            "greedy" => SamplingE::SamplingGreedy,
+            "temperature" => SamplingE::SamplingTemperature {
+                temperature: self.temperature,
+            },
+            "top_k" => SamplingE::SamplingTopK {
+                k: self.top_k,
+                temperature: self.temperature,
+            },
+            "top_p" => SamplingE::SamplingTopP {
+                p: self.top_p,
+                temperature: self.temperature,
+            },
+            name => return Err(usage_error(format!("Unknown sampler {}", name))),
+        };
+        Ok(Sampler::new(strategy, self.seed.unwrap_or(0)))
+    }
+
+    // Loads the tokenizer if it exists, otherwise trains one on `corpus` and
+    // saves it. Also returns `corpus` encoded, which training gives for free.
+    fn load_or_train_tokenizer(
+        self: &CliOptions,
+        corpus: &str,
+    ) -> Result<(Tokenizer, Vec<u32>), ErrorE> {
+        let tokenizer_location = self.tokenizer_path();
+        if Path::new(&tokenizer_location).exists() {
+            let mut tokenizer = Tokenizer::load(&tokenizer_location)?;
+            tokenizer.parse_special_tokens |= self.parse_special;
+            self.log(format!("Loaded the tokenizer from {}", tokenizer_location));
+            let ids = tokenizer.encode(corpus);
+            return Ok((tokenizer, ids));
+        }
+
+        if self.tokenizer.is_none() {
+            self.ensure_out_dir()?;
+        }
+        let pre_tokenizer = PreTokenizerE::from_name(&self.pre_tokenizer)
+            .ok_or_else(|| usage_error(format!("Unknown pre-tokenizer {}", self.pre_tokenizer)))?;
+        let mut trainer_config = BpeTrainerConfig {
+            min_pair_frequency: self.min_pair_freq,
+            max_token_len: self.max_token_len,
+            pre_tokenizer,
+            special_tokens: self.special_tokens.clone(),
+            parse_special_tokens: self.parse_special,
+            ..BpeTrainerConfig::default()
+        };
+        // Aim for the model's vocab_size when the config pins one.
+        let target_vocab_size = self.base_model_config()?.vocab_size as usize;
+        if target_vocab_size > 0 {
+            trainer_config.vocab_size = target_vocab_size;
+        }
+
+        let (tokenizer, ids) = tokenizer::tokenizer(corpus.to_string(), &trainer_config)?;
+        if target_vocab_size > 0 && tokenizer.vocab_size() != target_vocab_size {
+            return Err(ErrorE::ErrorTokenizer(format!(
+                "The trained tokenizer has {} tokens instead of the config's vocab_size {} \
+                 (the corpus may have too few frequent pairs, or too many characters)",
+                tokenizer.vocab_size(),
+                target_vocab_size
+            )));
+        }
+        tokenizer.save(&tokenizer_location)?;
+        self.log(format!("Saved the tokenizer to {}", tokenizer_location));
+        Ok((tokenizer, ids))
+    }
+
+    fn load_tokenizer(self: &CliOptions) -> Result<Tokenizer, ErrorE> {
+        let tokenizer_location = self.tokenizer_path();
+        if !Path::new(&tokenizer_location).exists() {
+            return Err(usage_error(format!(
+                "No tokenizer at {}; train one first or pass --tokenizer",
+                tokenizer_location
+            )));
+        }
+        let mut tokenizer = Tokenizer::load(&tokenizer_location)?;
+        tokenizer.parse_special_tokens |= self.parse_special;
+        Ok(tokenizer)
+    }
+
+    // Builds the model from `--weights` and the config when given, otherwise
+    // from the checkpoint.
+    fn load_model(self: &CliOptions, tokenizer: &Tokenizer) -> Result<Model, ErrorE> {
+        let model = match &self.weights {
+            Some(weights_location) => {
+                let mut model = Model::try_new(&self.model_config(tokenizer)?)?;
+                load_safetensors(weights_location, &mut model)?;
+                model
+            }
+            None => {
+                let checkpoint_location = self.checkpoint_path();
+                if !Path::new(&checkpoint_location).exists() {
+                    return Err(usage_error(format!(
+                        "No checkpoint at {}; train a model first or pass --checkpoint or --weights",
+                        checkpoint_location
+                    )));
+                }
+                load_checkpoint(&checkpoint_location)?.0
+            }
+        };
+
+        if model.config.vocab_size as usize != tokenizer.vocab_size() {
+            return Err(ErrorE::ErrorConfig(format!(
+                "The model's vocab of {} doesn't match the tokenizer's {}",
+                model.config.vocab_size,
+                tokenizer.vocab_size()
+            )));
+        }
+        Ok(model)
+    }
+
+    fn describe_model(self: &CliOptions, model: &Model) {
+        self.log(format!(
+            "Model: {} transformer blocks of dim {}, {} heads, a {} {} feed-forward, seq_len {}, vocab {}",
+            model.config.num_transformers,
+            model.config.dim,
+            model.config.n_heads,
+            model.config.feed_forward.activation().name(),
+            model.config.feed_forward.name(),
+            model.config.seq_len,
+            model.config.vocab_size
+        ));
+    }
+}
+
+fn run_train(options: &CliOptions) -> Result<(), ErrorE> {
+    let corpus_location = options.input("corpus file")?;
+    let content = read_file(corpus_location)?;
+    let (tokenizer, corpus) = options.load_or_train_tokenizer(&content)?;
+    options.log(format!(
+        "Tokenized {} into {} tokens over a vocab of {}",
+        corpus_location,
+        corpus.len(),
+        tokenizer.vocab_size()
+    ));
+    if corpus.len() < 2 {
+        return Err(ErrorE::ErrorUsage(format!(
+            "{} has fewer than two tokens to train on",
+            corpus_location
+        )));
+    }
+
+    let checkpoint_location = options.checkpoint_path();
+    let (mut model, mut optimizer, resume_from) = if Path::new(&checkpoint_location).exists() {
+        let (model, optimizer, position) = load_checkpoint(&checkpoint_location)?;
+        if options.config.is_some() || !options.overrides.is_empty() {
+            options.log(format!(
+                "Using the config stored in {}; --config and --set are ignored when resuming",
+                checkpoint_location
+            ));
+        }
+        let optimizer = match optimizer {
+            Some(optimizer) => {
+                if options.optimizer.is_some() || options.lr.is_some() {
+                    options.log(format!(
+                        "Using the optimizer stored in {}; --optimizer and --lr are ignored when resuming",
+                        checkpoint_location
+                    ));
+                }
+                optimizer
+            }
+            None => options.new_optimizer()?,
+        };
+        match position {
+            Some(position) => {
+                if options.seed.is_some_and(|seed| seed != position.seed) {
+                    options.log(format!(
+                        "Using the seed stored in {}; --seed is ignored when resuming",
+                        checkpoint_location
+                    ));
+                }
+                options.log(format!(
+                    "Resumed from {} at step {} (epoch {}, window {})",
+                    checkpoint_location,
+                    optimizer.step_count,
+                    position.epoch + 1,
+                    position.window
+                ));
+            }
+            None => options.log(format!(
+                "Resumed from {} at step {}; it has no training position, so epochs start over",
+                checkpoint_location, optimizer.step_count
+            )),
+        }
+        (model, optimizer, position)
+    } else {
+        let mut model = Model::try_new(&options.model_config(&tokenizer)?)?;
+        if let Some(weights_location) = &options.weights {
+            load_safetensors(weights_location, &mut model)?;
+            options.log(format!("Loaded weights from {}", weights_location));
+        }
+        (model, options.new_optimizer()?, None)
+    };
+
+    if model.config.vocab_size as usize != tokenizer.vocab_size() {
+        return Err(ErrorE::ErrorConfig(format!(
+            "The model's vocab of {} doesn't match the tokenizer's {}",
+            model.config.vocab_size,
+            tokenizer.vocab_size()
+        )));
+    }
+    options.describe_model(&model);
+
+    let train_config = TrainConfig {
+        epochs: options.epochs,
+        batch_size: options.batch_size,
+        random_windows: options.random_windows,
+        log_every: match options.verbosity {
+            0 => 0,
+            1 => 10,
+            _ => 1,
+        },
+        checkpoint_every: options.save_every,
+        seed: options.seed.unwrap_or(0),
+        resume_from,
+    };
+    if let Some(position) = resume_from.filter(|position| position.epoch >= options.epochs) {
+        options.log(format!(
+            "The checkpoint already finished {} epochs; pass a larger --epochs to keep training",
+            position.epoch
+        ));
+    }
+    if options.checkpoint.is_none() {
+        options.ensure_out_dir()?;
+    }
+    let stats = train(
+        &mut model,
+        &mut optimizer,
+        &corpus,
+        &train_config,
+        |progress| {
+            println!(
+                "epoch {} step {} | loss {:.4} | {:.0} tokens/sec | {:.1}s elapsed",
+                progress.epoch,
+                progress.step,
+                progress.loss,
+                progress.tokens_per_sec,
+                progress.elapsed_secs
+            );
+        },
+        |model, optimizer, position| {
+            save_checkpoint(&checkpoint_location, model, Some(optimizer), Some(position))?;
+            if options.verbosity >= 2 {
+                println!(
+                    "Saved a checkpoint to {} (epoch {}, window {})",
+                    checkpoint_location,
+                    position.epoch + 1,
+                    position.window
+                );
+            }
+            Ok(())
+        },
+    )?;
+    options.log(format!(
+        "Trained {} steps over {} tokens in {:.1}s, final loss {:.4}",
+        stats.steps, stats.tokens_seen, stats.elapsed_secs, stats.final_loss
+    ));
+
+    save_checkpoint(
+        &checkpoint_location,
+        &model,
+        Some(&optimizer),
+        Some(&stats.position),
+    )?;
+    options.log(format!("Saved a checkpoint to {}", checkpoint_location));
+
+    options.ensure_out_dir()?;
+    let safetensors_location = options.out_path("model.safetensors");
+    save_safetensors(&safetensors_location, &model)?;
+    options.log(format!("Exported the weights to {}", safetensors_location));
+
+    Ok(())
+}
+
+fn run_generate(options: &CliOptions) -> Result<(), ErrorE> {
+    let prompt = match (&options.prompt, options.positional.as_slice()) {
+        (Some(_), [_, ..]) => {
+            return Err(usage_error(
+                "Pass the prompt either with --prompt or as an argument, not both".to_string(),
+            ));
+        }
+        (Some(prompt), []) => prompt.clone(),
+        (None, []) => "The ".to_string(),
+        (None, positional) => positional.join(" "),
+    };
+
+    let tokenizer = options.load_tokenizer()?;
+    let model = options.load_model(&tokenizer)?;
+    options.describe_model(&model);
+
+    let mut sampler = options.sampler()?;
+    println!(
+        "{}",
+        generate(
+            &model,
+            &tokenizer,
+            &prompt,
+            options.max_tokens,
+            &mut sampler
+        )?
+    );
+    Ok(())
+}
+
+fn run_tokenize(options: &CliOptions) -> Result<(), ErrorE> {
+    let corpus_location = options.input("text file")?;
+    let content = read_file(corpus_location)?;
+    let (tokenizer, ids) = options.load_or_train_tokenizer(&content)?;
+
+    let num_chars = content.chars().count();
+    println!(
+        "{} chars -> {} tokens over a vocab of {} ({:.2} chars per token)",
+        num_chars,
+        ids.len(),
+        tokenizer.vocab_size(),
+        num_chars as f32 / ids.len().max(1) as f32
+    );
+    if options.verbosity >= 2 {
+        let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        println!("{}", ids.join(" "));
+    }
+    Ok(())
+}
+
+fn run_eval(options: &CliOptions) -> Result<(), ErrorE> {
+    let corpus_location = options.input("text file")?;
+    let content = read_file(corpus_location)?;
+    let tokenizer = options.load_tokenizer()?;
+    let model = options.load_model(&tokenizer)?;
+    options.describe_model(&model);
+
+    let corpus = tokenizer.encode(&content);
+    if corpus.len() < 2 {
+        return Err(ErrorE::ErrorUsage(format!(
+            "{} has fewer than two tokens to evaluate",
+            corpus_location
+        )));
+    }
+
+    let stats = evaluate(&model, &corpus)?;
+    println!(
+        "{} tokens | loss {:.4} | perplexity {:.2}",
+        stats.tokens, stats.loss, stats.perplexity
+    );
+    Ok(())
+}
+
+fn run_info(options: &CliOptions) -> Result<(), ErrorE> {
+    let location = options.input("file")?;
+    let bytes = fs::read(location).map_err(|error| ErrorE::io(location, error))?;
+
+    if bytes.starts_with(b"TGPTCKPT") {
+        let (model, optimizer, position) = load_checkpoint(location)?;
+        println!("{}: checkpoint", location);
+        println!("config: {}", model.config.to_json().to_json_string());
+        let num_params: usize = model.params().iter().map(|p| p.vals.len()).sum();
+        println!("parameters: {}", num_params);
+        match optimizer {
+            Some(optimizer) => println!("optimizer: step {}", optimizer.step_count),
+            None => println!("optimizer: none"),
+        }
+        if let Some(position) = position {
+            println!(
+                "position: epoch {} window {} seed {}",
+                position.epoch, position.window, position.seed
+            );
+        }
+    } else if bytes.starts_with(b"tinygpt-tokenizer") {
+        let tokenizer = Tokenizer::load(location)?;
+        println!("{}: tokenizer", location);
+        println!("vocab: {}", tokenizer.vocab_size());
+        println!("merges: {}", tokenizer.merges().len());
+        println!("pre_tokenizer: {}", tokenizer.pre_tokenizer.name());
+        let special_tokens: Vec<String> = tokenizer
+            .special_tokens()
+            .iter()
+            .map(|(val, id)| format!("{}={}", val, id))
+            .collect();
+        println!("special_tokens: {}", special_tokens.join(" "));
+    } else {
+        let SafeTensorsFile { metadata, tensors } = read_safetensors(location)?;
+        println!("{}: safetensors", location);
+        for (key, value) in metadata {
+            println!("{}: {}", key, value);
+        }
+        let mut names: Vec<&String> = tensors.keys().collect();
+        names.sort();
+        let num_params: usize = tensors.values().map(|t| t.vals.len()).sum();
+        println!("tensors: {} ({} parameters)", names.len(), num_params);
+        if options.verbosity >= 2 {
+            for name in names {
+                println!("  {} {:?}", name, tensors[name].shape);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,442 @@
+use crate::{
+    error::ErrorE,
+    json::{JsonValue, parse_json},
+    utils::{FeedForwardKindE, NNActivationE, read_file},
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelConfig {
+    pub seq_len: i32,
+    pub dim: i32,
+    pub eps: f32,
+    // 0 means "use the tokenizer's vocab size".
+    pub vocab_size: i32,
+    pub num_transformers: i32,
+    pub n_heads: i32,
+    pub hidden_nodes: i32,
+    pub feed_forward: FeedForwardKindE,
+    // Token used to pad short windows out to `seq_len`, if the tokenizer
+    // has one.
+    pub pad_id: Option<u32>,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self {
+            seq_len: 32,
+            dim: 8,
+            eps: 0.003,
+            vocab_size: 0,
+            num_transformers: 4,
+            n_heads: 2,
+            hidden_nodes: 32,
+            feed_forward: FeedForwardKindE::FeedForwardMLP {
+                activation: NNActivationE::NNActivationGELU,
+            },
+            pad_id: None,
+        }
+    }
+}
+
+impl ModelConfig {
+    // Reads a `.toml` or `.json` file. Keys that are left out keep their
+    // default values.
+    pub fn load(filename: &str) -> Result<ModelConfig, ErrorE> {
+        let text = read_file(filename)?;
+        let value = if filename.ends_with(".toml") {
+            parse_toml(&text)?
+        } else if filename.ends_with(".json") {
+            parse_json(&text)
+                .map_err(|error| ErrorE::ErrorConfig(format!("{}: {}", filename, error)))?
+        } else {
+            return Err(ErrorE::ErrorConfig(format!(
+                "{}: config files must end in .toml or .json",
+                filename
+            )));
+        };
+
+        let config = ModelConfig::from_json(&value)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub(crate) fn from_json(value: &JsonValue) -> Result<ModelConfig, ErrorE> {
+        let JsonValue::Object(entries) = value else {
+            return Err(ErrorE::ErrorConfig(
+                "Model config is not an object".to_string(),
+            ));
+        };
+
+        let mut config = ModelConfig::default();
+        let mut activation: Option<NNActivationE> = None;
+        let mut gated: Option<bool> = None;
+
+        for (i, (key, value)) in entries.iter().enumerate() {
+            if entries[..i].iter().any(|(k, _)| k == key) {
+                return Err(ErrorE::ErrorConfig(format!(
+                    "Model config has a duplicate key {}",
+                    key
+                )));
+            }
+            let bad_value =
+                || ErrorE::ErrorConfig(format!("Model config has a bad value for {}", key));
+            let number = || value.as_f64().ok_or_else(bad_value);
+            let integer = || {
+                number().and_then(|n| {
+                    if n.fract() == 0.0 && n >= 0.0 && n <= i32::MAX as f64 {
+                        Ok(n as i32)
+                    } else {
+                        Err(bad_value())
+                    }
+                })
+            };
+
+            match key.as_str() {
+                "seq_len" => config.seq_len = integer()?,
+                "dim" => config.dim = integer()?,
+                "eps" => config.eps = number()? as f32,
+                "vocab_size" => config.vocab_size = integer()?,
+                "num_transformers" => config.num_transformers = integer()?,
+                "n_heads" => config.n_heads = integer()?,
+                "hidden_nodes" => config.hidden_nodes = integer()?,
+                "pad_id" => config.pad_id = Some(integer()? as u32),
+                "activation" => {
+                    let name = value.as_str().ok_or_else(bad_value)?;
+                    activation = Some(NNActivationE::from_name(name).ok_or_else(bad_value)?);
+                }
+                "feed_forward" => match value.as_str() {
+                    Some("mlp") => gated = Some(false),
+                    Some("glu") => gated = Some(true),
+                    Some("swiglu") => {
+                        gated = Some(true);
+                        activation.get_or_insert(NNActivationE::NNActivationSiLU);
+                    }
+                    Some("geglu") => {
+                        gated = Some(true);
+                        activation.get_or_insert(NNActivationE::NNActivationGELU);
+                    }
+                    _ => return Err(bad_value()),
+                },
+                _ => {
+                    return Err(ErrorE::ErrorConfig(format!(
+                        "Model config has an unknown key {}",
+                        key
+                    )));
+                }
+            }
+        }
+
+        let activation = activation.unwrap_or(config.feed_forward.activation());
+        config.feed_forward = match gated {
+            Some(true) => FeedForwardKindE::FeedForwardGLU { activation },
+            _ => FeedForwardKindE::FeedForwardMLP { activation },
+        };
+
+        Ok(config)
+    }
+
+    // Applies a `key=value` override on top of the current values. Naming a
+    // gated variant (`swiglu`, `geglu`) also switches to its activation.
+    pub fn set(self: &mut ModelConfig, key: &str, value: &str) -> Result<(), ErrorE> {
+        let value = match value.parse::<f64>() {
+            Ok(n) => JsonValue::Number(n),
+            Err(_) => JsonValue::String(value.to_string()),
+        };
+        let JsonValue::Object(mut entries) = self.to_json() else {
+            unreachable!();
+        };
+
+        if key == "feed_forward" && matches!(value.as_str(), Some("swiglu" | "geglu")) {
+            entries.retain(|(k, _)| k != "activation");
+        }
+        match entries.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value,
+            None => entries.push((key.to_string(), value)),
+        }
+
+        *self = ModelConfig::from_json(&JsonValue::Object(entries))?;
+        Ok(())
+    }
+
+    pub(crate) fn to_json(self: &ModelConfig) -> JsonValue {
+        let number = |n: f64| JsonValue::Number(n);
+        let mut entries = vec![
+            ("seq_len".to_string(), number(self.seq_len as f64)),
+            ("dim".to_string(), number(self.dim as f64)),
+            // Going through the string keeps 0.003 from becoming 0.0030000000260...
+            (
+                "eps".to_string(),
+                number(self.eps.to_string().parse().unwrap()),
+            ),
+            ("vocab_size".to_string(), number(self.vocab_size as f64)),
+            (
+                "num_transformers".to_string(),
+                number(self.num_transformers as f64),
+            ),
+            ("n_heads".to_string(), number(self.n_heads as f64)),
+            ("hidden_nodes".to_string(), number(self.hidden_nodes as f64)),
+            (
+                "feed_forward".to_string(),
+                JsonValue::String(self.feed_forward.name().to_string()),
+            ),
+            (
+                "activation".to_string(),
+                JsonValue::String(self.feed_forward.activation().name().to_string()),
+            ),
+        ];
+        if let Some(pad_id) = self.pad_id {
+            entries.push(("pad_id".to_string(), number(pad_id as f64)));
+        }
+        JsonValue::Object(entries)
+    }
+
+    // How many f32 weights a model built from this config holds, or None if
+    // that doesn't fit in a usize. Assumes the config has passed `validate`.
+    pub fn num_params(self: &ModelConfig) -> Option<usize> {
+        let dim = self.dim as usize;
+        let hidden = self.hidden_nodes as usize;
+        let vocab_size = self.vocab_size as usize;
+
+        let feed_forward = match self.feed_forward {
+            // Weights and biases of the hidden layer and of the projection back.
+            FeedForwardKindE::FeedForwardMLP { .. } => dim
+                .checked_mul(hidden)?
+                .checked_mul(2)?
+                .checked_add(hidden)?
+                .checked_add(dim)?,
+            // w_gate, w_up and w_down.
+            FeedForwardKindE::FeedForwardGLU { .. } => dim.checked_mul(hidden)?.checked_mul(3)?,
+        };
+        // Two layer norms plus w_q, w_k, w_v and w_o.
+        let block = dim
+            .checked_mul(4)?
+            .checked_add(dim.checked_mul(dim)?.checked_mul(4)?)?
+            .checked_add(feed_forward)?;
+
+        // The embedding table and w_o, then gamma and beta.
+        vocab_size
+            .checked_mul(dim)?
+            .checked_mul(2)?
+            .checked_add(dim * 2)?
+            .checked_add(block.checked_mul(self.num_transformers as usize)?)
+    }
+
+    // Catches combinations the modules would otherwise panic on (or silently
+    // get wrong) before any weights are built.
+    pub fn validate(self: &ModelConfig) -> Result<(), ErrorE> {
+        let positive = [
+            ("seq_len", self.seq_len),
+            ("dim", self.dim),
+            ("num_transformers", self.num_transformers),
+            ("n_heads", self.n_heads),
+            ("hidden_nodes", self.hidden_nodes),
+        ];
+        for (name, val) in positive {
+            if val <= 0 {
+                return Err(ErrorE::ErrorConfig(format!(
+                    "Model config {} must be positive, got {}",
+                    name, val
+                )));
+            }
+        }
+
+        if self.vocab_size < 0 {
+            return Err(ErrorE::ErrorConfig(format!(
+                "Model config vocab_size can't be negative, got {}",
+                self.vocab_size
+            )));
+        }
+        if !self.eps.is_finite() || self.eps <= 0.0 {
+            return Err(ErrorE::ErrorConfig(format!(
+                "Model config eps must be positive and finite, got {}",
+                self.eps
+            )));
+        }
+        if let Some(pad_id) = self.pad_id
+            && self.vocab_size > 0
+            && pad_id >= self.vocab_size as u32
+        {
+            return Err(ErrorE::ErrorConfig(format!(
+                "Model config pad_id {} is outside the vocab of {}",
+                pad_id, self.vocab_size
+            )));
+        }
+        if self.dim % self.n_heads != 0 {
+            return Err(ErrorE::ErrorConfig(format!(
+                "Model config dim {} isn't divisible by n_heads {}",
+                self.dim, self.n_heads
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Parses the flat subset of TOML a model config needs: `key = value` lines
+// with string, number or boolean values and `#` comments.
+fn parse_toml(text: &str) -> Result<JsonValue, ErrorE> {
+    let mut entries: Vec<(String, JsonValue)> = vec![];
+
+    for (i, line) in text.lines().enumerate() {
+        let bad_line = |message: &str| {
+            ErrorE::ErrorConfig(format!("Invalid TOML on line {}: {}", i + 1, message))
+        };
+
+        let line = strip_toml_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(bad_line("expected key = value"));
+        };
+        let key = key.trim().trim_matches('"').to_string();
+        let value = value.trim();
+
+        let value = if value.starts_with('"') {
+            if value.len() < 2 || !value.ends_with('"') {
+                return Err(bad_line("unterminated string"));
+            }
+            JsonValue::String(value[1..value.len() - 1].to_string())
+        } else if value == "true" || value == "false" {
+            JsonValue::Bool(value == "true")
+        } else {
+            value
+                .replace('_', "")
+                .parse::<f64>()
+                .map(JsonValue::Number)
+                .map_err(|_| bad_line(&format!("unsupported value {}", value)))?
+        };
+
+        if entries.iter().any(|(k, _)| *k == key) {
+            return Err(bad_line(&format!("duplicate key {}", key)));
+        }
+        entries.push((key, value));
+    }
+
+    Ok(JsonValue::Object(entries))
+}
+
+fn strip_toml_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_path;
+    use std::fs;
+
+    // Writes `text` to a file named `file_name` and loads it.
+    fn load_text(file_name: &str, text: &str) -> Result<ModelConfig, ErrorE> {
+        let path = temp_path(file_name);
+        fs::write(&path, text).unwrap();
+        let result = ModelConfig::load(&path);
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn toml_and_json_give_the_same_config() {
+        let toml = load_text(
+            "config.toml",
+            "# a small model\n\
+             seq_len = 16\n\
+             dim = 12 # three heads of 4\n\
+             n_heads = 3\n\
+             eps = 1e-5\n\
+             hidden_nodes = 1_024\n\
+             feed_forward = \"swiglu\"\n\
+             pad_id = 2\n",
+        )
+        .unwrap();
+        let json = load_text(
+            "config.json",
+            r#"{"seq_len": 16, "dim": 12, "n_heads": 3, "eps": 0.00001,
+                "hidden_nodes": 1024, "feed_forward": "swiglu", "pad_id": 2}"#,
+        )
+        .unwrap();
+
+        assert_eq!(toml, json);
+        assert_eq!(
+            toml,
+            ModelConfig {
+                seq_len: 16,
+                dim: 12,
+                n_heads: 3,
+                eps: 1e-5,
+                hidden_nodes: 1024,
+                feed_forward: FeedForwardKindE::FeedForwardGLU {
+                    activation: NNActivationE::NNActivationSiLU,
+                },
+                pad_id: Some(2),
+                ..ModelConfig::default()
+            }
+        );
+    }
+
+    #[test]
+    fn bad_configs_are_rejected() {
+        let cases = [
+            ("unknown.toml", "dim = 8\nheads = 2\n"),
+            ("unknown.json", r#"{"dim": 8, "heads": 2}"#),
+            ("duplicate.toml", "dim = 8\ndim = 16\n"),
+            ("duplicate.json", r#"{"dim": 8, "dim": 16}"#),
+            ("heads.toml", "dim = 10\nn_heads = 4\n"),
+            ("heads.json", r#"{"dim": 10, "n_heads": 4}"#),
+        ];
+        for (file_name, text) in cases {
+            let result = load_text(file_name, text);
+            assert!(
+                matches!(result, Err(ErrorE::ErrorConfig(_))),
+                "{}",
+                file_name
+            );
+        }
+    }
+
+    #[test]
+    fn set_overrides_one_key() {
+        let mut config = ModelConfig::default();
+        config.set("activation", "relu").unwrap();
+        config.set("dim", "16").unwrap();
+        assert_eq!(config.dim, 16);
+
+        // The gated variants bring their own activation.
+        config.set("feed_forward", "swiglu").unwrap();
+        assert_eq!(
+            config.feed_forward,
+            FeedForwardKindE::FeedForwardGLU {
+                activation: NNActivationE::NNActivationSiLU,
+            }
+        );
+        // A plain "glu" keeps the current one.
+        config.set("activation", "relu").unwrap();
+        config.set("feed_forward", "glu").unwrap();
+        assert_eq!(
+            config.feed_forward,
+            FeedForwardKindE::FeedForwardGLU {
+                activation: NNActivationE::NNActivationRELU,
+            }
+        );
+
+        assert!(config.set("no_such_key", "1").is_err());
+        assert!(config.set("feed_forward", "nope").is_err());
+    }
+
+    #[test]
+    fn non_finite_eps_is_rejected() {
+        for eps in ["inf", "-inf", "NaN", "0"] {
+            let mut config = ModelConfig::default();
+            config.set("eps", eps).unwrap();
+            assert!(config.validate().is_err(), "eps={}", eps);
+        }
+    }
+}
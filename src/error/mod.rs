@@ -0,0 +1,64 @@
+use std::{error::Error, fmt, io};
+
+#[derive(Debug)]
+pub enum ErrorE {
+    ErrorIO {
+        path: String,
+        source: io::Error,
+    },
+    // Two operands whose shapes can't be combined by `op`.
+    ErrorShapeMismatch {
+        op: String,
+        left: (i32, i32),
+        right: (i32, i32),
+    },
+    ErrorConfig(String),
+    ErrorTokenizer(String),
+    // Malformed or unsupported checkpoint, safetensors or JSON data.
+    ErrorCheckpointFormat(String),
+    // Bad command-line input.
+    ErrorUsage(String),
+}
+
+impl ErrorE {
+    pub fn io(path: &str, source: io::Error) -> ErrorE {
+        ErrorE::ErrorIO {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    pub fn shape_mismatch(op: &str, left: (i32, i32), right: (i32, i32)) -> ErrorE {
+        ErrorE::ErrorShapeMismatch {
+            op: op.to_string(),
+            left,
+            right,
+        }
+    }
+}
+
+impl fmt::Display for ErrorE {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorE::ErrorIO { path, source } => write!(f, "{}: {}", path, source),
+            ErrorE::ErrorShapeMismatch { op, left, right } => write!(
+                f,
+                "[{}] shapes {}x{} and {}x{} don't match",
+                op, left.0, left.1, right.0, right.1
+            ),
+            ErrorE::ErrorConfig(message) => write!(f, "config error: {}", message),
+            ErrorE::ErrorTokenizer(message) => write!(f, "tokenizer error: {}", message),
+            ErrorE::ErrorCheckpointFormat(message) => write!(f, "format error: {}", message),
+            ErrorE::ErrorUsage(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for ErrorE {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ErrorE::ErrorIO { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::{autograd::Tape, error::ErrorE, model::Model, tokenizer::Tokenizer};
+
+pub enum SamplingE {
+    SamplingGreedy,
+    SamplingTemperature { temperature: f32 },
+    SamplingTopK { k: usize, temperature: f32 },
+    SamplingTopP { p: f32, temperature: f32 },
+}
+
+pub struct Sampler {
+    pub strategy: SamplingE,
+    rng: StdRng,
+}
+
+impl Sampler {
+    pub fn new(strategy: SamplingE, seed: u64) -> Self {
+        Self {
+            strategy,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    // Picks an index from a row of vocab probabilities.
+    pub fn sample(self: &mut Sampler, probs: &[f32]) -> usize {
+        let (temperature, k, p) = match self.strategy {
+            SamplingE::SamplingGreedy => return argmax(probs),
+            SamplingE::SamplingTemperature { temperature } => (temperature, probs.len(), 1.0),
+            SamplingE::SamplingTopK { k, temperature } => (temperature, k, 1.0),
+            SamplingE::SamplingTopP { p, temperature } => (temperature, probs.len(), p),
+        };
+
+        if temperature <= 0.0 {
+            return argmax(probs);
+        }
+
+        // Scaling logits by 1/T is the same as raising probabilities to 1/T.
+        let mut candidates: Vec<(usize, f32)> = probs
+            .iter()
+            .map(|prob| prob.powf(1.0 / temperature))
+            .enumerate()
+            .collect();
+        let total: f32 = candidates.iter().map(|(_, prob)| prob).sum();
+        for (_, prob) in candidates.iter_mut() {
+            *prob /= total;
+        }
+
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+        candidates.truncate(k.max(1));
+
+        let mut cumulative = 0f32;
+        let mut nucleus_len = candidates.len();
+        for (i, (_, prob)) in candidates.iter().enumerate() {
+            cumulative += prob;
+            if cumulative >= p {
+                nucleus_len = i + 1;
+                break;
+            }
+        }
+        candidates.truncate(nucleus_len);
+
+        let total: f32 = candidates.iter().map(|(_, prob)| prob).sum();
+        let mut threshold = self.rng.gen_range(0.0..1.0) * total;
+        for (index, prob) in candidates.iter() {
+            threshold -= prob;
+            if threshold <= 0.0 {
+                return *index;
+            }
+        }
+
+        candidates.last().map(|(index, _)| *index).unwrap_or(0)
+    }
+}
+
+fn argmax(probs: &[f32]) -> usize {
+    probs
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(index, _)| index)
+        .unwrap_or(0)
+}
+
+pub fn generate(
+    model: &Model,
+    tokenizer: &Tokenizer,
+    prompt: &str,
+    max_new_tokens: i32,
+    sampler: &mut Sampler,
+) -> Result<String, ErrorE> {
+    // An empty prompt starts from BOS, which isn't part of the output.
+    let mut tokens = tokenizer.encode(prompt);
+    if tokens.is_empty() {
+        match tokenizer.bos_id() {
+            Some(bos_id) => tokens.push(bos_id),
+            None => return Ok(prompt.to_string()),
+        }
+    }
+
+    let prompt_len = tokens.len();
+    let mut cache = model.new_cache();
+    let mut new_tokens = tokens.clone();
+
+    // Special tokens other than EOS are never sampled, and sampling EOS ends
+    // the text.
+    let eos_id = tokenizer.eos_id();
+    let suppressed: Vec<usize> = tokenizer
+        .special_tokens()
+        .iter()
+        .filter(|(_, id)| Some(*id) != eos_id)
+        .map(|(_, id)| *id as usize)
+        .collect();
+
+    // The prompt fills the cache in one pass; after that each step only feeds
+    // the token it just sampled.
+    for _ in 0..max_new_tokens {
+        let mut tape = Tape::new();
+        let vocab_pred = model.try_forward_cached(&mut tape, &mut cache, &new_tokens)?;
+
+        let probs = tape.value(vocab_pred);
+        let last_row = (probs.rows - 1) as usize * probs.cols as usize;
+        let mut row = probs.vals[last_row..last_row + probs.cols as usize].to_vec();
+        for id in suppressed.iter() {
+            if let Some(prob) = row.get_mut(*id) {
+                *prob = 0.0;
+            }
+        }
+
+        let token_id = sampler.sample(&row) as u32;
+        if Some(token_id) == eos_id {
+            break;
+        }
+        tokens.push(token_id);
+        new_tokens = vec![token_id];
+    }
+
+    Ok(format!(
+        "{}{}",
+        prompt,
+        tokenizer.decode(&tokens[prompt_len..])
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::ModelConfig,
+        tokenizer::{BOS_TOKEN, BpeTrainerConfig, NUM_BYTE_TOKENS, tokenizer},
+        utils::seed_rng,
+    };
+
+    const PROBS: [f32; 5] = [0.05, 0.4, 0.1, 0.3, 0.15];
+
+    fn draws(strategy: SamplingE, seed: u64) -> Vec<usize> {
+        let mut sampler = Sampler::new(strategy, seed);
+        (0..200).map(|_| sampler.sample(&PROBS)).collect()
+    }
+
+    fn drawn_indices(draws: &[usize]) -> Vec<usize> {
+        let mut indices = draws.to_vec();
+        indices.sort_unstable();
+        indices.dedup();
+        indices
+    }
+
+    #[test]
+    fn greedy_returns_the_argmax() {
+        assert_eq!(drawn_indices(&draws(SamplingE::SamplingGreedy, 0)), vec![1]);
+        let zero_temperature = SamplingE::SamplingTemperature { temperature: 0.0 };
+        assert_eq!(drawn_indices(&draws(zero_temperature, 0)), vec![1]);
+    }
+
+    #[test]
+    fn top_k_only_returns_the_k_most_likely() {
+        let top_k = SamplingE::SamplingTopK {
+            k: 2,
+            temperature: 1.0,
+        };
+        assert_eq!(drawn_indices(&draws(top_k, 0)), vec![1, 3]);
+    }
+
+    #[test]
+    fn top_p_stays_inside_the_nucleus() {
+        // 0.4 alone is short of 0.6 and 0.4 + 0.3 isn't, so the nucleus is
+        // indices 1 and 3.
+        let top_p = SamplingE::SamplingTopP {
+            p: 0.6,
+            temperature: 1.0,
+        };
+        assert_eq!(drawn_indices(&draws(top_p, 0)), vec![1, 3]);
+
+        let top_p = SamplingE::SamplingTopP {
+            p: 0.8,
+            temperature: 1.0,
+        };
+        assert_eq!(drawn_indices(&draws(top_p, 0)), vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_draws() {
+        let temperature = || SamplingE::SamplingTemperature { temperature: 1.0 };
+        assert_eq!(draws(temperature(), 3), draws(temperature(), 3));
+        assert_ne!(draws(temperature(), 3), draws(temperature(), 4));
+        assert_eq!(drawn_indices(&draws(temperature(), 3)), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn an_empty_prompt_starts_from_bos() {
+        let config = BpeTrainerConfig {
+            vocab_size: NUM_BYTE_TOKENS + 4,
+            ..BpeTrainerConfig::default()
+        };
+        let (mut tokenizer, _) = tokenizer(String::new(), &config).unwrap();
+        tokenizer.parse_special_tokens = true;
+        seed_rng(0);
+        let model = Model::new(&ModelConfig {
+            seq_len: 6,
+            vocab_size: tokenizer.vocab_size() as i32,
+            num_transformers: 1,
+            ..ModelConfig::default()
+        });
+
+        // Sampler seed 0 draws EOS straight away with these weights.
+        let sample = |prompt: &str| {
+            let temperature = SamplingE::SamplingTemperature { temperature: 1.0 };
+            generate(
+                &model,
+                &tokenizer,
+                prompt,
+                5,
+                &mut Sampler::new(temperature, 1),
+            )
+            .unwrap()
+        };
+        let text = sample("");
+        assert!(!text.is_empty());
+        assert_eq!(format!("{}{}", BOS_TOKEN, text), sample(BOS_TOKEN));
+    }
+}
@@ -0,0 +1,255 @@
+use std::{iter::Peekable, str::Chars};
+
+use crate::error::ErrorE;
+
+// Arrays and objects nested deeper than this are rejected rather than
+// recursed into, so a hostile header can't overflow the stack.
+const MAX_JSON_DEPTH: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    // Keeps keys in document order so written files are stable.
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    pub fn get(self: &JsonValue, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(self: &JsonValue) -> Option<f64> {
+        match self {
+            JsonValue::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self: &JsonValue) -> Option<&str> {
+        match self {
+            JsonValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(self: &JsonValue) -> Option<&Vec<JsonValue>> {
+        match self {
+            JsonValue::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn to_json_string(self: &JsonValue) -> String {
+        let mut out = String::new();
+        write_value(self, &mut out);
+        out
+    }
+}
+
+fn write_value(value: &JsonValue, out: &mut String) {
+    match value {
+        JsonValue::Null => out.push_str("null"),
+        JsonValue::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        JsonValue::Number(n) => out.push_str(&n.to_string()),
+        JsonValue::String(s) => write_string(s, out),
+        JsonValue::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(item, out);
+            }
+            out.push(']');
+        }
+        JsonValue::Object(entries) => {
+            out.push('{');
+            for (i, (key, item)) in entries.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_string(key, out);
+                out.push(':');
+                write_value(item, out);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn write_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+pub fn parse_json(text: &str) -> Result<JsonValue, ErrorE> {
+    let mut chars = text.chars().peekable();
+    let value = parse_value(&mut chars, 0)?;
+    skip_whitespace(&mut chars);
+    if chars.peek().is_some() {
+        return Err(json_error("trailing characters"));
+    }
+    Ok(value)
+}
+
+fn json_error(message: &str) -> ErrorE {
+    ErrorE::ErrorCheckpointFormat(format!("Invalid JSON: {}", message))
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+fn expect_literal(chars: &mut Peekable<Chars>, literal: &str) -> Result<(), ErrorE> {
+    for expected in literal.chars() {
+        if chars.next() != Some(expected) {
+            return Err(json_error(&format!("expected {}", literal)));
+        }
+    }
+    Ok(())
+}
+
+// `depth` counts the arrays and objects around the value.
+fn parse_value(chars: &mut Peekable<Chars>, depth: usize) -> Result<JsonValue, ErrorE> {
+    skip_whitespace(chars);
+    if depth >= MAX_JSON_DEPTH && matches!(chars.peek(), Some('[' | '{')) {
+        return Err(json_error("nesting too deep"));
+    }
+    match chars.peek() {
+        Some('n') => expect_literal(chars, "null").map(|_| JsonValue::Null),
+        Some('t') => expect_literal(chars, "true").map(|_| JsonValue::Bool(true)),
+        Some('f') => expect_literal(chars, "false").map(|_| JsonValue::Bool(false)),
+        Some('"') => parse_string(chars).map(JsonValue::String),
+        Some('[') => {
+            chars.next();
+            let mut items = vec![];
+            skip_whitespace(chars);
+            if chars.peek() == Some(&']') {
+                chars.next();
+                return Ok(JsonValue::Array(items));
+            }
+            loop {
+                items.push(parse_value(chars, depth + 1)?);
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some(']') => return Ok(JsonValue::Array(items)),
+                    _ => return Err(json_error("expected , or ]")),
+                }
+            }
+        }
+        Some('{') => {
+            chars.next();
+            let mut entries = vec![];
+            skip_whitespace(chars);
+            if chars.peek() == Some(&'}') {
+                chars.next();
+                return Ok(JsonValue::Object(entries));
+            }
+            loop {
+                skip_whitespace(chars);
+                let key = parse_string(chars)?;
+                skip_whitespace(chars);
+                if chars.next() != Some(':') {
+                    return Err(json_error("expected :"));
+                }
+                entries.push((key, parse_value(chars, depth + 1)?));
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some('}') => return Ok(JsonValue::Object(entries)),
+                    _ => return Err(json_error("expected , or }")),
+                }
+            }
+        }
+        Some(c) if *c == '-' || c.is_ascii_digit() => {
+            let mut number = String::new();
+            while let Some(c) = chars.peek()
+                && (c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+            {
+                number.push(*c);
+                chars.next();
+            }
+            number
+                .parse::<f64>()
+                .map(JsonValue::Number)
+                .map_err(|_| json_error(&format!("bad number {}", number)))
+        }
+        _ => Err(json_error("unexpected character")),
+    }
+}
+
+fn parse_hex4(chars: &mut Peekable<Chars>) -> Result<u32, ErrorE> {
+    let hex: String = (0..4).filter_map(|_| chars.next()).collect();
+    u32::from_str_radix(&hex, 16).map_err(|_| json_error("bad \\u escape"))
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, ErrorE> {
+    if chars.next() != Some('"') {
+        return Err(json_error("expected string"));
+    }
+
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(s),
+            Some('\\') => match chars.next() {
+                Some('"') => s.push('"'),
+                Some('\\') => s.push('\\'),
+                Some('/') => s.push('/'),
+                Some('b') => s.push('\u{8}'),
+                Some('f') => s.push('\u{c}'),
+                Some('n') => s.push('\n'),
+                Some('r') => s.push('\r'),
+                Some('t') => s.push('\t'),
+                Some('u') => {
+                    let mut code = parse_hex4(chars)?;
+                    // Characters outside the BMP arrive as a surrogate pair.
+                    if (0xD800..0xDC00).contains(&code) {
+                        expect_literal(chars, "\\u")?;
+                        let low = parse_hex4(chars)?;
+                        code =
+                            0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                    }
+                    s.push(char::from_u32(code).ok_or_else(|| json_error("bad \\u escape"))?);
+                }
+                _ => return Err(json_error("bad escape")),
+            },
+            Some(c) => s.push(c),
+            None => return Err(json_error("unterminated string")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse_json(&nested(MAX_JSON_DEPTH)).is_ok());
+        assert!(parse_json(&nested(MAX_JSON_DEPTH + 1)).is_err());
+        assert!(parse_json(&"[".repeat(200_000)).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! A small GPT built from scratch: a byte-level BPE tokenizer, a tape-based
+//! autograd, transformer blocks, training, sampling and checkpoint/safetensors
+//! I/O.
+//!
+//! ```no_run
+//! use tinygpt::{Sampler, SamplingE, Tokenizer, generate, load_checkpoint};
+//!
+//! # fn main() -> Result<(), tinygpt::ErrorE> {
+//! let tokenizer = Tokenizer::load("out/tokenizer.txt")?;
+//! let (model, _, _) = load_checkpoint("out/model.ckpt")?;
+//! let mut sampler = Sampler::new(SamplingE::SamplingGreedy, 0);
+//! println!("{}", generate(&model, &tokenizer, "The ", 32, &mut sampler)?);
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod attention;
+pub mod autograd;
+pub mod checkpoint;
+pub mod cli;
+pub mod config;
+pub mod embedder;
+pub mod error;
+pub mod generate;
+pub(crate) mod json;
+pub mod model;
+pub mod optimizer;
+pub(crate) mod parallel;
+pub mod pretokenizer;
+pub mod safetensors;
+#[cfg(test)]
+mod test_utils;
+pub mod tokenizer;
+pub mod train;
+pub mod transformer;
+pub mod utils;
+
+pub use autograd::Tape;
+pub use checkpoint::{load_checkpoint, save_checkpoint};
+pub use config::ModelConfig;
+pub use error::ErrorE;
+pub use generate::{Sampler, SamplingE, generate};
+pub use model::{DecoderCache, Model};
+pub use optimizer::{Optimizer, OptimizerE};
+pub use parallel::set_num_threads;
+pub use pretokenizer::PreTokenizerE;
+pub use safetensors::{load_safetensors, save_safetensors};
+pub use tokenizer::{BpeTrainerConfig, Tokenizer};
+pub use train::{
+    EvalStats, TrainConfig, TrainPosition, TrainProgress, TrainStats, evaluate, train,
+};
+pub use transformer::Transformer;
+pub use utils::{FeedForwardKindE, MatrixF32, NNActivationE};
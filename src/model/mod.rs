@@ -1,67 +1,330 @@
-use std::{cell::RefCell, rc::Rc, usize};
-
 use crate::{
-    attention::generate_seq_matrix, tokenizer::DLLToken, transformer::Transformer, utils::MatrixF32,
+    attention::{KVCache, generate_seq_matrix},
+    autograd::{Param, ParamMut, Tape, Var},
+    config::ModelConfig,
+    embedder::Embedder,
+    error::ErrorE,
+    transformer::Transformer,
+    utils::MatrixF32,
 };
 
+// Per-block key/value caches for incremental decoding. `pos` is the position
+// the next token will be embedded at.
+pub struct DecoderCache {
+    pub pos: i32,
+    pub blocks: Vec<KVCache>,
+    // The cached tokens, oldest first, kept to rebuild the cache when the
+    // window slides.
+    pub tokens: Vec<u32>,
+}
+
 pub struct Model {
-    pub seq_len: i32,
-    pub dim: i32,
-    pub eps: f32,
+    pub config: ModelConfig,
     pub gamma: Vec<f32>,
     pub beta: Vec<f32>,
-    pub vocab_size: i32,
     pub w_o: MatrixF32,
-    token_id_map: Vec<i32>,
+    pub embedder: Embedder,
+    pub transformers: Vec<Transformer>,
 }
 
 impl Model {
-    pub fn new(seq_len: i32, eps: f32, dim: i32, vocab_size: i32, token_id_map: Vec<i32>) -> Self {
-        Self {
-            seq_len,
-            dim,
-            eps,
+    // Panics on a config that doesn't pass `ModelConfig::validate` or has no
+    // vocab size; `try_new` reports those as errors instead.
+    pub fn new(config: &ModelConfig) -> Self {
+        Model::try_new(config).unwrap_or_else(|error| panic!("[model] {}", error))
+    }
+
+    pub fn try_new(config: &ModelConfig) -> Result<Self, ErrorE> {
+        config.validate()?;
+        if config.vocab_size <= 0 {
+            return Err(ErrorE::ErrorConfig(
+                "vocab_size must be set before building a model".to_string(),
+            ));
+        }
+
+        let dim = config.dim;
+        let transformers: Vec<Transformer> = (0..config.num_transformers)
+            .map(|_| Transformer::new(config))
+            .collect();
+
+        Ok(Self {
+            config: config.clone(),
             gamma: vec![1.0; dim as usize],
             beta: vec![0.0; dim as usize],
-            vocab_size,
-            w_o: MatrixF32::new_rand_weight(dim as usize, vocab_size as usize),
-            token_id_map,
+            w_o: MatrixF32::new_rand_weight(dim as usize, config.vocab_size as usize),
+            embedder: Embedder::new(config.vocab_size, dim),
+            transformers,
+        })
+    }
+
+    pub fn params(self: &Model) -> Vec<Param<'_>> {
+        let mut params = vec![
+            Param::matrix("embedding".to_string(), &self.embedder.table),
+            Param::vector("gamma".to_string(), &self.gamma),
+            Param::vector("beta".to_string(), &self.beta),
+            Param::matrix("w_o".to_string(), &self.w_o),
+        ];
+
+        for (i, transformer) in self.transformers.iter().enumerate() {
+            transformer.params(&format!("blocks.{}", i), &mut params);
+        }
+
+        params
+    }
+
+    pub fn params_mut(self: &mut Model) -> Vec<ParamMut<'_>> {
+        let mut params = vec![
+            ParamMut::matrix("embedding".to_string(), &mut self.embedder.table),
+            ParamMut::vector("gamma".to_string(), &mut self.gamma),
+            ParamMut::vector("beta".to_string(), &mut self.beta),
+            ParamMut::matrix("w_o".to_string(), &mut self.w_o),
+        ];
+
+        for (i, transformer) in self.transformers.iter_mut().enumerate() {
+            transformer.params_mut(&format!("blocks.{}", i), &mut params);
+        }
+
+        params
+    }
+
+    pub fn new_cache(self: &Model) -> DecoderCache {
+        DecoderCache {
+            pos: 0,
+            blocks: (0..self.transformers.len())
+                .map(|_| KVCache::new(self.config.dim))
+                .collect(),
+            tokens: vec![],
         }
     }
 
-    pub fn forward(
+    // Token ids outside the vocab would otherwise panic deep inside the
+    // embedding lookup.
+    fn check_tokens(self: &Model, tokens: &[u32]) -> Result<(), ErrorE> {
+        if tokens.is_empty() {
+            return Err(ErrorE::ErrorTokenizer(
+                "the model needs at least one token".to_string(),
+            ));
+        }
+        match tokens
+            .iter()
+            .find(|id| **id >= self.config.vocab_size as u32)
+        {
+            Some(id) => Err(ErrorE::ErrorTokenizer(format!(
+                "token id {} is outside the model's vocab of {}",
+                id, self.config.vocab_size
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn try_forward(
         self: &Model,
-        dll_head: Rc<RefCell<DLLToken>>,
-    ) -> (MatrixF32, Option<Rc<RefCell<DLLToken>>>, Vec<i32>) {
-        let (mut seq_matrix, next_seq_head, target_token_ids) =
-            generate_seq_matrix(self.seq_len, self.dim, &dll_head);
-        let mut norm_seq = seq_matrix.layer_norm(self.eps, &self.gamma, &self.beta);
-
-        let num_transformers = 4;
-        let mut transformers: Vec<Transformer> = (0..num_transformers)
-            .map(|_| Transformer::new(self.dim, self.seq_len))
-            .collect();
+        tape: &mut Tape,
+        tokens: &[u32],
+    ) -> Result<(Var, Vec<u32>), ErrorE> {
+        self.check_tokens(tokens)?;
+        Ok(self.forward(tape, tokens))
+    }
 
-        for transformer in transformers.iter_mut() {
-            seq_matrix = transformer.run(&norm_seq);
-            norm_seq = seq_matrix.layer_norm(self.eps, &self.gamma, &self.beta);
+    pub fn try_forward_cached(
+        self: &Model,
+        tape: &mut Tape,
+        cache: &mut DecoderCache,
+        tokens: &[u32],
+    ) -> Result<Var, ErrorE> {
+        self.check_tokens(tokens)?;
+        if cache.blocks.len() != self.transformers.len() {
+            return Err(ErrorE::ErrorConfig(format!(
+                "the cache has {} blocks but the model has {}",
+                cache.blocks.len(),
+                self.transformers.len()
+            )));
         }
+        Ok(self.forward_cached(tape, cache, tokens))
+    }
 
-        norm_seq = &norm_seq * &self.w_o;
-        norm_seq.softmax_row();
-        (norm_seq, next_seq_head, target_token_ids)
+    pub fn forward(self: &Model, tape: &mut Tape, tokens: &[u32]) -> (Var, Vec<u32>) {
+        let (seq_matrix, target_token_ids) = generate_seq_matrix(
+            tape,
+            self.config.seq_len,
+            &self.embedder,
+            tokens,
+            self.config.pad_id,
+        );
+        (self.decode(tape, seq_matrix, None), target_token_ids)
     }
 
-    pub fn cross_entropy(self: &Model, vocab_pred: MatrixF32, target: Vec<i32>) -> f32 {
-        let mut loss = 0f32;
+    // Runs only `tokens` through the model, attending over everything already
+    // in `cache`, and returns their vocab probabilities (for at most the last
+    // `seq_len` of them), the same as `forward` over `cache.tokens` would.
+    //
+    // Training only ever embeds positions below `seq_len`, so once the cache
+    // would grow past that it's rebuilt from position 0 with only the last
+    // `seq_len / 2` tokens (or all of `tokens`, up to `seq_len`). That leaves
+    // room for the next `seq_len / 2` tokens, so a full rebuild happens only
+    // that often and the steps in between cost one token each.
+    pub fn forward_cached(
+        self: &Model,
+        tape: &mut Tape,
+        cache: &mut DecoderCache,
+        tokens: &[u32],
+    ) -> Var {
+        let seq_len = self.config.seq_len as usize;
+        cache.tokens.extend_from_slice(tokens);
+        if cache.tokens.len() <= seq_len {
+            let vocab_ids: Vec<i32> = tokens.iter().map(|id| *id as i32).collect();
+            let seq_matrix = self.embedder.embed_at(tape, &vocab_ids, cache.pos);
+            cache.pos += tokens.len() as i32;
+            return self.decode(tape, seq_matrix, Some(cache));
+        }
+
+        let keep = (seq_len / 2).max(tokens.len()).min(seq_len);
+        cache.tokens.drain(..cache.tokens.len() - keep);
+        for block in cache.blocks.iter_mut() {
+            *block = KVCache::new(self.config.dim);
+        }
+        let vocab_ids: Vec<i32> = cache.tokens.iter().map(|id| *id as i32).collect();
+        let seq_matrix = self.embedder.embed_at(tape, &vocab_ids, 0);
+        cache.pos = keep as i32;
+        let probs = self.decode(tape, seq_matrix, Some(cache));
+        if tokens.len() >= keep {
+            return probs;
+        }
+
+        // Only the rows of the tokens just passed in.
+        let probs = tape.value(probs);
+        let num_rows = tokens.len();
+        let cols = probs.cols as usize;
+        let mut new_probs = MatrixF32::new(num_rows as i32, probs.cols);
+        new_probs.vals = probs.vals[(keep - num_rows) * cols..].to_vec();
+        tape.constant(new_probs)
+    }
+
+    fn decode(
+        self: &Model,
+        tape: &mut Tape,
+        seq_matrix: Var,
+        mut cache: Option<&mut DecoderCache>,
+    ) -> Var {
+        let gamma = tape.param_vec("gamma", &self.gamma);
+        let beta = tape.param_vec("beta", &self.beta);
+        let mut norm_seq = tape.layer_norm(seq_matrix, self.config.eps, gamma, beta);
 
-        for i in 0..self.seq_len {
-            let target_token_id = target[i as usize];
-            let target_vocab_index = self.token_id_map[target_token_id as usize];
-            let pred = vocab_pred[(i, target_vocab_index)];
-            loss += -pred.ln();
+        for (i, transformer) in self.transformers.iter().enumerate() {
+            let block_cache = cache.as_mut().map(|cache| &mut cache.blocks[i]);
+            let seq_matrix = transformer.run(tape, &format!("blocks.{}", i), norm_seq, block_cache);
+            norm_seq = tape.layer_norm(seq_matrix, self.config.eps, gamma, beta);
         }
 
-        loss / self.seq_len as f32
+        let w_o = tape.param("w_o", &self.w_o);
+        let logits = tape.matmul(norm_seq, w_o);
+        tape.softmax_row(logits)
+    }
+
+    pub fn cross_entropy(self: &Model, tape: &mut Tape, vocab_pred: Var, target: Vec<u32>) -> Var {
+        let target_ids: Vec<i32> = target.iter().map(|id| *id as i32).collect();
+        tape.cross_entropy(vocab_pred, &target_ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::test_model,
+        utils::{FeedForwardKindE, NNActivationE},
+    };
+
+    fn last_row(tape: &Tape, probs: Var) -> Vec<f32> {
+        let probs = tape.value(probs);
+        let cols = probs.cols as usize;
+        probs.vals[probs.vals.len() - cols..].to_vec()
+    }
+
+    fn max_abs_diff(a: &[f32], b: &[f32]) -> f32 {
+        a.iter()
+            .zip(b)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max)
+    }
+
+    // Feeds a 3 token prompt and then one token at a time, comparing each
+    // step's prediction with a full forward pass over the tokens the cache
+    // holds. The window first slides after step 3, then every 3 steps.
+    #[test]
+    fn cached_forward_matches_uncached() {
+        let model = test_model(0);
+        let seq_len = model.config.seq_len as usize;
+        let tokens: Vec<u32> = (0..15).map(|i| (i * 7 + 3) % 20).collect();
+        let mut cache = model.new_cache();
+
+        let mut fed = 3;
+        let mut num_slides = 0;
+        let mut tape = Tape::new();
+        let mut cached = model.forward_cached(&mut tape, &mut cache, &tokens[..fed]);
+        assert_eq!(tape.value(cached).rows, 3);
+        loop {
+            let cached_row = last_row(&tape, cached);
+
+            let window = &tokens[fed - cache.tokens.len()..fed];
+            assert!(window.len() >= seq_len / 2 && window.len() <= seq_len);
+            assert_eq!(cache.tokens, window);
+            let mut full_tape = Tape::new();
+            let (full, _) = model.forward(&mut full_tape, window);
+            let diff = max_abs_diff(&cached_row, &last_row(&full_tape, full));
+            assert!(diff < 1e-6, "{} tokens: differs by {}", fed, diff);
+
+            if fed == tokens.len() {
+                break;
+            }
+            let cached_len = cache.tokens.len();
+            tape = Tape::new();
+            cached = model.forward_cached(&mut tape, &mut cache, &tokens[fed..fed + 1]);
+            assert_eq!(tape.value(cached).rows, 1);
+            if cache.tokens.len() < cached_len {
+                num_slides += 1;
+            }
+            fed += 1;
+        }
+        // At 7, 10 and 13 tokens.
+        assert_eq!(num_slides, 3);
+        assert_eq!(cache.pos as usize, cache.tokens.len());
+    }
+
+    #[test]
+    fn cached_forward_keeps_the_last_window_of_a_long_prompt() {
+        let model = test_model(0);
+        let seq_len = model.config.seq_len as usize;
+        let tokens: Vec<u32> = (0..10).map(|i| (i * 3 + 1) % 20).collect();
+
+        let mut tape = Tape::new();
+        let mut cache = model.new_cache();
+        let cached = model.forward_cached(&mut tape, &mut cache, &tokens);
+        assert_eq!(tape.value(cached).rows, seq_len as i32);
+
+        let mut full_tape = Tape::new();
+        let (full, _) = model.forward(&mut full_tape, &tokens[tokens.len() - seq_len..]);
+        assert_eq!(tape.value(cached).vals, full_tape.value(full).vals);
+    }
+
+    #[test]
+    fn num_params_matches_the_built_model() {
+        for feed_forward in [
+            FeedForwardKindE::FeedForwardMLP {
+                activation: NNActivationE::NNActivationGELU,
+            },
+            FeedForwardKindE::FeedForwardGLU {
+                activation: NNActivationE::NNActivationSiLU,
+            },
+        ] {
+            let model = Model::new(&ModelConfig {
+                vocab_size: 20,
+                hidden_nodes: 12,
+                feed_forward,
+                ..ModelConfig::default()
+            });
+            let count: usize = model.params().iter().map(|param| param.vals.len()).sum();
+            assert_eq!(model.config.num_params(), Some(count));
+        }
     }
 }
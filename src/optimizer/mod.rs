@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+
+use crate::{autograd::ParamMut, error::ErrorE, utils::MatrixF32};
+
+pub enum OptimizerE {
+    OptimizerSGD {
+        momentum: f32,
+    },
+    OptimizerAdam {
+        beta1: f32,
+        beta2: f32,
+        eps: f32,
+        weight_decay: f32,
+    },
+    OptimizerAdamW {
+        beta1: f32,
+        beta2: f32,
+        eps: f32,
+        weight_decay: f32,
+    },
+}
+
+pub struct Optimizer {
+    pub kind: OptimizerE,
+    pub lr: f32,
+    pub step_count: i32,
+    // SGD keeps its velocity in `first_moments`; Adam variants use both maps.
+    pub first_moments: HashMap<String, Vec<f32>>,
+    pub second_moments: HashMap<String, Vec<f32>>,
+}
+
+impl Optimizer {
+    pub fn new(kind: OptimizerE, lr: f32) -> Self {
+        Self {
+            kind,
+            lr,
+            step_count: 0,
+            first_moments: HashMap::new(),
+            second_moments: HashMap::new(),
+        }
+    }
+
+    pub fn sgd(lr: f32) -> Self {
+        Self::new(OptimizerE::OptimizerSGD { momentum: 0.0 }, lr)
+    }
+
+    pub fn momentum(lr: f32, momentum: f32) -> Self {
+        Self::new(OptimizerE::OptimizerSGD { momentum }, lr)
+    }
+
+    pub fn adam(lr: f32) -> Self {
+        Self::new(
+            OptimizerE::OptimizerAdam {
+                beta1: 0.9,
+                beta2: 0.999,
+                eps: 1e-8,
+                weight_decay: 0.0,
+            },
+            lr,
+        )
+    }
+
+    pub fn adamw(lr: f32, weight_decay: f32) -> Self {
+        Self::new(
+            OptimizerE::OptimizerAdamW {
+                beta1: 0.9,
+                beta2: 0.999,
+                eps: 1e-8,
+                weight_decay,
+            },
+            lr,
+        )
+    }
+
+    // Panics where `try_step` would return an error.
+    pub fn step(self: &mut Optimizer, params: Vec<ParamMut>, grads: &HashMap<String, MatrixF32>) {
+        self.try_step(params, grads)
+            .unwrap_or_else(|error| panic!("[optimizer_step] {}", error))
+    }
+
+    // Fails without touching any weights if a gradient or a stored moment
+    // isn't the shape of its parameter, e.g. when the optimizer state came
+    // from a different model.
+    pub fn try_step(
+        self: &mut Optimizer,
+        params: Vec<ParamMut>,
+        grads: &HashMap<String, MatrixF32>,
+    ) -> Result<(), ErrorE> {
+        for param in params.iter() {
+            let shape = (param.rows, param.cols);
+            if let Some(grad) = grads.get(&param.name)
+                && (grad.rows, grad.cols) != shape
+            {
+                return Err(ErrorE::shape_mismatch(
+                    &format!("optimizer_step {}", param.name),
+                    shape,
+                    (grad.rows, grad.cols),
+                ));
+            }
+            for moments in [&self.first_moments, &self.second_moments] {
+                if let Some(moment) = moments.get(&param.name)
+                    && moment.len() != param.vals.len()
+                {
+                    return Err(ErrorE::shape_mismatch(
+                        &format!("optimizer_step {} moments", param.name),
+                        shape,
+                        (1, moment.len() as i32),
+                    ));
+                }
+            }
+        }
+
+        self.step_count += 1;
+
+        for param in params {
+            let Some(grad) = grads.get(&param.name) else {
+                continue;
+            };
+
+            match self.kind {
+                OptimizerE::OptimizerSGD { momentum } => {
+                    self.sgd_step(param, &grad.vals, momentum);
+                }
+                OptimizerE::OptimizerAdam {
+                    beta1,
+                    beta2,
+                    eps,
+                    weight_decay,
+                } => {
+                    // Classic Adam folds L2 weight decay into the gradient.
+                    let grad: Vec<f32> = grad
+                        .vals
+                        .iter()
+                        .zip(param.vals.iter())
+                        .map(|(g, w)| g + weight_decay * w)
+                        .collect();
+                    self.adam_step(param, &grad, beta1, beta2, eps, 0.0);
+                }
+                OptimizerE::OptimizerAdamW {
+                    beta1,
+                    beta2,
+                    eps,
+                    weight_decay,
+                } => {
+                    self.adam_step(param, &grad.vals, beta1, beta2, eps, weight_decay);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn sgd_step(self: &mut Optimizer, param: ParamMut, grad: &[f32], momentum: f32) {
+        if momentum == 0.0 {
+            for (w, g) in param.vals.iter_mut().zip(grad.iter()) {
+                *w -= self.lr * g;
+            }
+            return;
+        }
+
+        let velocity = self
+            .first_moments
+            .entry(param.name)
+            .or_insert_with(|| vec![0.0; grad.len()]);
+
+        for ((w, v), g) in param.vals.iter_mut().zip(velocity.iter_mut()).zip(grad) {
+            *v = momentum * *v + g;
+            *w -= self.lr * *v;
+        }
+    }
+
+    fn adam_step(
+        self: &mut Optimizer,
+        param: ParamMut,
+        grad: &[f32],
+        beta1: f32,
+        beta2: f32,
+        eps: f32,
+        weight_decay: f32,
+    ) {
+        let m = self
+            .first_moments
+            .entry(param.name.clone())
+            .or_insert_with(|| vec![0.0; grad.len()]);
+        let v = self
+            .second_moments
+            .entry(param.name)
+            .or_insert_with(|| vec![0.0; grad.len()]);
+
+        let bias_correction1 = 1.0 - beta1.powi(self.step_count);
+        let bias_correction2 = 1.0 - beta2.powi(self.step_count);
+
+        for i in 0..grad.len() {
+            m[i] = beta1 * m[i] + (1.0 - beta1) * grad[i];
+            v[i] = beta2 * v[i] + (1.0 - beta2) * grad[i] * grad[i];
+
+            let m_hat = m[i] / bias_correction1;
+            let v_hat = v[i] / bias_correction2;
+
+            // AdamW decays the weights directly instead of through the gradient.
+            param.vals[i] -=
+                self.lr * (m_hat / (v_hat.sqrt() + eps) + weight_decay * param.vals[i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Applies one step per gradient to `weights`, as a single parameter "w".
+    fn run_steps(optimizer: &mut Optimizer, weights: &mut [f32], steps: &[&[f32]]) {
+        for grad in steps {
+            let mut grads: HashMap<String, MatrixF32> = HashMap::new();
+            let mut grad_matrix = MatrixF32::new(1, grad.len() as i32);
+            grad_matrix.vals = grad.to_vec();
+            grads.insert("w".to_string(), grad_matrix);
+            optimizer.step(vec![ParamMut::vector("w".to_string(), weights)], &grads);
+        }
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!(
+                (a - e).abs() <= 1e-5 * e.abs().max(1e-3),
+                "{:?} != {:?}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn momentum_accumulates_velocity() {
+        let mut optimizer = Optimizer::momentum(0.1, 0.9);
+        let mut weights = [1.0f32];
+        // v = 0.5, w = 1 - 0.1 * 0.5
+        run_steps(&mut optimizer, &mut weights, &[&[0.5]]);
+        assert_close(&weights, &[0.95]);
+        // v = 0.9 * 0.5 + 0.5 = 0.95, w = 0.95 - 0.1 * 0.95
+        run_steps(&mut optimizer, &mut weights, &[&[0.5]]);
+        assert_close(&weights, &[0.855]);
+        assert_close(&optimizer.first_moments["w"], &[0.95]);
+    }
+
+    #[test]
+    fn adam_corrects_moment_bias() {
+        let mut optimizer = Optimizer::adam(0.1);
+        let mut weights = [1.0f32];
+        // Bias correction makes the first step exactly lr * sign(grad).
+        run_steps(&mut optimizer, &mut weights, &[&[0.5]]);
+        assert_close(&weights, &[0.9]);
+        // m = 0.07, v = 0.00031225; corrected by 1 - 0.9^2 and 1 - 0.999^2
+        // they're 0.368421 and 0.156203, so w = 0.9 - 0.1 * 0.368421 / 0.395225.
+        run_steps(&mut optimizer, &mut weights, &[&[0.25]]);
+        assert_close(&weights, &[0.806782]);
+        assert_close(&optimizer.first_moments["w"], &[0.07]);
+        assert_close(&optimizer.second_moments["w"], &[0.00031225]);
+    }
+
+    #[test]
+    fn adamw_decays_weights_outside_the_gradient() {
+        let grad: &[f32] = &[0.5, -0.25];
+
+        // Adam adds 0.1 * w to the gradient, which the first step normalises
+        // away: each weight still moves by exactly lr.
+        let mut adam = Optimizer::new(
+            OptimizerE::OptimizerAdam {
+                beta1: 0.9,
+                beta2: 0.999,
+                eps: 1e-8,
+                weight_decay: 0.1,
+            },
+            0.1,
+        );
+        let mut weights = [1.0f32, -2.0];
+        run_steps(&mut adam, &mut weights, &[grad]);
+        assert_close(&weights, &[0.9, -1.9]);
+
+        // AdamW moves by lr * (sign(grad) + 0.1 * w).
+        let mut adamw = Optimizer::adamw(0.1, 0.1);
+        let mut weights = [1.0f32, -2.0];
+        run_steps(&mut adamw, &mut weights, &[grad]);
+        assert_close(&weights, &[0.89, -1.88]);
+    }
+
+    #[test]
+    fn mismatched_shapes_are_errors_that_leave_the_weights_alone() {
+        let mut grads: HashMap<String, MatrixF32> = HashMap::new();
+        grads.insert("w".to_string(), MatrixF32::new(1, 3));
+        let mut weights = [1.0f32, 2.0];
+        let mut optimizer = Optimizer::adam(0.1);
+        let result = optimizer.try_step(
+            vec![ParamMut::vector("w".to_string(), &mut weights)],
+            &grads,
+        );
+        assert!(matches!(result, Err(ErrorE::ErrorShapeMismatch { .. })));
+
+        // Moments left over from a parameter of another size.
+        grads.insert("w".to_string(), MatrixF32::new(1, 2));
+        let mut optimizer = Optimizer::momentum(0.1, 0.9);
+        optimizer
+            .first_moments
+            .insert("w".to_string(), vec![1.0; 5]);
+        let result = optimizer.try_step(
+            vec![ParamMut::vector("w".to_string(), &mut weights)],
+            &grads,
+        );
+        assert!(matches!(result, Err(ErrorE::ErrorShapeMismatch { .. })));
+
+        assert_eq!(weights, [1.0, 2.0]);
+        assert_eq!(optimizer.step_count, 0);
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+// 0 means one thread per available core.
+static NUM_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+// Below this much work (roughly multiply-adds) an op stays on the calling
+// thread, since spawning costs more than it saves.
+const PARALLEL_MIN_WORK: usize = 1 << 15;
+
+thread_local! {
+    // Takes precedence over NUM_THREADS for kernels called from this thread,
+    // so tests can pin the count without racing each other.
+    static LOCAL_NUM_THREADS: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+// Sets how many threads the matrix kernels split rows over. 0 uses every
+// core and 1 keeps everything on the calling thread. Each row is always
+// computed the same way, so results don't depend on the thread count.
+pub fn set_num_threads(num_threads: usize) {
+    NUM_THREADS.store(num_threads, Ordering::Relaxed);
+}
+
+pub fn num_threads() -> usize {
+    let num_threads = LOCAL_NUM_THREADS
+        .with(Cell::get)
+        .unwrap_or_else(|| NUM_THREADS.load(Ordering::Relaxed));
+    match num_threads {
+        0 => thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1),
+        n => n,
+    }
+}
+
+// Splits `vals` into contiguous runs of whole rows and calls
+// `f(first_row, rows)` on each, in parallel when there's enough work. The
+// calling thread takes the first run and scoped threads the rest.
+pub fn for_each_row_chunk<F>(vals: &mut [f32], row_len: usize, work_per_row: usize, f: F)
+where
+    F: Fn(usize, &mut [f32]) + Sync,
+{
+    if row_len == 0 || vals.is_empty() {
+        return;
+    }
+
+    let num_rows = vals.len() / row_len;
+    let max_threads = (num_rows * work_per_row / PARALLEL_MIN_WORK).max(1);
+    let threads = num_threads().min(max_threads).min(num_rows);
+    if threads <= 1 {
+        f(0, vals);
+        return;
+    }
+
+    let rows_per_thread = num_rows.div_ceil(threads);
+    thread::scope(|scope| {
+        let f = &f;
+        let mut chunks = vals.chunks_mut(rows_per_thread * row_len).enumerate();
+        let first_chunk = chunks.next();
+        for (i, chunk) in chunks {
+            scope.spawn(move || f(i * rows_per_thread, chunk));
+        }
+        if let Some((_, chunk)) = first_chunk {
+            f(0, chunk);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{MatrixF32, seed_rng};
+
+    fn with_num_threads<T>(num_threads: usize, f: impl FnOnce() -> T) -> T {
+        LOCAL_NUM_THREADS.with(|local| local.set(Some(num_threads)));
+        let result = f();
+        LOCAL_NUM_THREADS.with(|local| local.set(None));
+        result
+    }
+
+    // Runs `f` once on the calling thread only and once split over four
+    // threads. The count is set for this test's thread alone, so tests
+    // running alongside can't change it.
+    fn single_and_multi_threaded<T>(f: impl Fn() -> T) -> (T, T) {
+        let single = with_num_threads(1, &f);
+        let multi = with_num_threads(4, &f);
+        (single, multi)
+    }
+
+    #[test]
+    fn row_chunks_cover_every_row_once() {
+        let rows = 1000;
+        let (single, multi) = single_and_multi_threaded(|| {
+            let mut vals = vec![0f32; rows * 3];
+            for_each_row_chunk(&mut vals, 3, PARALLEL_MIN_WORK, |first_row, chunk| {
+                for (i, row) in chunk.chunks_exact_mut(3).enumerate() {
+                    for val in row.iter_mut() {
+                        *val += (first_row + i) as f32;
+                    }
+                }
+            });
+            vals
+        });
+
+        let expected: Vec<f32> = (0..rows).flat_map(|i| [i as f32; 3]).collect();
+        assert_eq!(single, expected);
+        assert_eq!(multi, expected);
+    }
+
+    #[test]
+    fn kernels_match_across_thread_counts() {
+        // Large enough for every kernel to use all four threads.
+        seed_rng(0);
+        let a = MatrixF32::new_rand_weight(1100, 130);
+        let b = MatrixF32::new_rand_weight(130, 90);
+        let b_t = b.transposed();
+        let gamma: Vec<f32> = (0..130).map(|j| 1.0 + j as f32 / 130.0).collect();
+        let beta: Vec<f32> = (0..130).map(|j| j as f32 / 260.0).collect();
+
+        let (single, multi) = single_and_multi_threaded(|| {
+            let product = a.checked_mul(&b).unwrap();
+            let product_t = a.view().checked_mul(&b_t.t()).unwrap();
+            let mut softmax = product.clone();
+            softmax.softmax_row();
+            let norm = a.layer_norm(1e-5, &gamma, &beta);
+            let sum = a.checked_add(&norm).unwrap();
+            [product, product_t, softmax, norm, sum].map(|m| m.vals)
+        });
+
+        assert_eq!(single, multi);
+        assert_eq!(single[0], single[1]);
+    }
+}
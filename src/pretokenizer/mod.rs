@@ -0,0 +1,131 @@
+// How text is cut into chunks before BPE. Merges never cross a chunk
+// boundary, so each chunk can be encoded (and cached) on its own.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PreTokenizerE {
+    // The whole text is one chunk.
+    PreTokenizerNone,
+    // GPT-2's split: contractions, then runs of letters, digits or other
+    // symbols (each optionally led by one space), then whitespace.
+    PreTokenizerGPT2,
+}
+
+impl PreTokenizerE {
+    pub fn name(self: &PreTokenizerE) -> &'static str {
+        match self {
+            PreTokenizerE::PreTokenizerNone => "none",
+            PreTokenizerE::PreTokenizerGPT2 => "gpt2",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PreTokenizerE> {
+        match name {
+            "none" => Some(PreTokenizerE::PreTokenizerNone),
+            "gpt2" => Some(PreTokenizerE::PreTokenizerGPT2),
+            _ => None,
+        }
+    }
+
+    // The chunks always concatenate back to `text`.
+    pub fn split<'a>(self: &PreTokenizerE, text: &'a str) -> Vec<&'a str> {
+        match self {
+            PreTokenizerE::PreTokenizerNone if text.is_empty() => vec![],
+            PreTokenizerE::PreTokenizerNone => vec![text],
+            PreTokenizerE::PreTokenizerGPT2 => split_gpt2(text),
+        }
+    }
+}
+
+const CONTRACTIONS: [&str; 7] = ["s", "t", "re", "ve", "m", "ll", "d"];
+
+// Hand-rolled equivalent of GPT-2's
+// 's|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+
+fn split_gpt2(text: &str) -> Vec<&str> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let byte_at = |i: usize| chars.get(i).map_or(text.len(), |(b, _)| *b);
+
+    let mut chunks: Vec<&str> = vec![];
+    let mut start = 0usize;
+    while start < chars.len() {
+        let end = gpt2_chunk_end(&chars, start);
+        chunks.push(&text[byte_at(start)..byte_at(end)]);
+        start = end;
+    }
+    chunks
+}
+
+fn is_symbol(c: char) -> bool {
+    !c.is_whitespace() && !c.is_alphabetic() && !c.is_numeric()
+}
+
+// Index of the char just past the chunk that starts at `start`.
+fn gpt2_chunk_end(chars: &[(usize, char)], start: usize) -> usize {
+    let at = |i: usize| chars.get(i).map(|(_, c)| *c);
+
+    if at(start) == Some('\'') {
+        for suffix in CONTRACTIONS {
+            if suffix
+                .chars()
+                .enumerate()
+                .all(|(k, c)| at(start + 1 + k) == Some(c))
+            {
+                return start + 1 + suffix.len();
+            }
+        }
+    }
+
+    let body = if at(start) == Some(' ') {
+        start + 1
+    } else {
+        start
+    };
+    let classes: [fn(char) -> bool; 3] = [char::is_alphabetic, char::is_numeric, is_symbol];
+    for class in classes {
+        if at(body).is_some_and(class) {
+            let mut end = body + 1;
+            while at(end).is_some_and(class) {
+                end += 1;
+            }
+            return end;
+        }
+    }
+
+    // A whitespace run gives up its last char to the word that follows it,
+    // so " word" keeps its leading space.
+    let mut end = start + 1;
+    while at(end).is_some_and(char::is_whitespace) {
+        end += 1;
+    }
+    if end - start > 1 && end < chars.len() {
+        end - 1
+    } else {
+        end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gpt2_split_matches_the_reference_regex() {
+        let cases: [(&str, &[&str]); 11] = [
+            ("", &[]),
+            ("don't", &["don", "'t"]),
+            ("we'll they've", &["we", "'ll", " they", "'ve"]),
+            // A contraction only splits off right at the apostrophe.
+            (" 's", &[" '", "s"]),
+            ("a  b", &["a", " ", " b"]),
+            ("a  ", &["a", "  "]),
+            ("\n\na", &["\n", "\n", "a"]),
+            ("abc123!!? x42", &["abc", "123", "!!?", " x", "42"]),
+            ("3.14 + 2", &["3", ".", "14", " +", " 2"]),
+            ("naïve café", &["naïve", " café"]),
+            ("\t x", &["\t", " x"]),
+        ];
+        for (text, expected) in cases {
+            let chunks = PreTokenizerE::PreTokenizerGPT2.split(text);
+            assert_eq!(chunks, expected, "{:?}", text);
+            assert_eq!(chunks.concat(), text);
+        }
+    }
+}
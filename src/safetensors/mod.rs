@@ -0,0 +1,231 @@
+use std::{collections::HashMap, fs};
+
+use crate::{
+    error::ErrorE,
+    json::{JsonValue, parse_json},
+    model::Model,
+};
+
+pub struct SafeTensor {
+    pub shape: Vec<usize>,
+    pub vals: Vec<f32>,
+}
+
+pub struct SafeTensorsFile {
+    // `__metadata__` entries; non-string values are kept as JSON text.
+    pub metadata: Vec<(String, String)>,
+    pub tensors: HashMap<String, SafeTensor>,
+}
+
+// Writes every model parameter as an F32 tensor named like `blocks.0.attn.w_q`.
+// The model config goes into `__metadata__` as strings.
+pub fn save_safetensors(filename: &str, model: &Model) -> Result<(), ErrorE> {
+    let JsonValue::Object(config) = model.config.to_json() else {
+        unreachable!();
+    };
+    let mut metadata = vec![("format".to_string(), JsonValue::String("pt".to_string()))];
+    for (key, value) in config {
+        let value = match value {
+            JsonValue::String(s) => s,
+            value => value.to_json_string(),
+        };
+        metadata.push((key, JsonValue::String(value)));
+    }
+    let metadata = JsonValue::Object(metadata);
+
+    let mut header: Vec<(String, JsonValue)> = vec![("__metadata__".to_string(), metadata)];
+    let mut data: Vec<u8> = vec![];
+
+    for param in model.params() {
+        let start = data.len();
+        for val in param.vals {
+            data.extend_from_slice(&val.to_le_bytes());
+        }
+
+        header.push((
+            param.name,
+            JsonValue::Object(vec![
+                ("dtype".to_string(), JsonValue::String("F32".to_string())),
+                (
+                    "shape".to_string(),
+                    JsonValue::Array(vec![
+                        JsonValue::Number(param.rows as f64),
+                        JsonValue::Number(param.cols as f64),
+                    ]),
+                ),
+                (
+                    "data_offsets".to_string(),
+                    JsonValue::Array(vec![
+                        JsonValue::Number(start as f64),
+                        JsonValue::Number(data.len() as f64),
+                    ]),
+                ),
+            ]),
+        ));
+    }
+
+    // The data section should start on an 8 byte boundary, so the header is
+    // padded with spaces.
+    let mut header_json = JsonValue::Object(header).to_json_string();
+    while !header_json.len().is_multiple_of(8) {
+        header_json.push(' ');
+    }
+
+    let mut bytes: Vec<u8> = Vec::with_capacity(8 + header_json.len() + data.len());
+    bytes.extend_from_slice(&(header_json.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(header_json.as_bytes());
+    bytes.extend_from_slice(&data);
+
+    fs::write(filename, bytes).map_err(|error| ErrorE::io(filename, error))
+}
+
+pub fn read_safetensors(filename: &str) -> Result<SafeTensorsFile, ErrorE> {
+    let bytes = fs::read(filename).map_err(|error| ErrorE::io(filename, error))?;
+    let bad_file =
+        |message: &str| ErrorE::ErrorCheckpointFormat(format!("{}: {}", filename, message));
+
+    if bytes.len() < 8 {
+        return Err(bad_file("file is too short"));
+    }
+    let header_end = usize::try_from(u64::from_le_bytes(bytes[..8].try_into().unwrap()))
+        .ok()
+        .and_then(|header_len| header_len.checked_add(8))
+        .filter(|header_end| *header_end <= bytes.len())
+        .ok_or_else(|| bad_file("header runs past the end of the file"))?;
+
+    let header_text =
+        std::str::from_utf8(&bytes[8..header_end]).map_err(|_| bad_file("header is not UTF-8"))?;
+    let header = parse_json(header_text)?;
+    let JsonValue::Object(entries) = header else {
+        return Err(bad_file("header is not a JSON object"));
+    };
+
+    let data = &bytes[header_end..];
+    let mut metadata: Vec<(String, String)> = vec![];
+    let mut tensors: HashMap<String, SafeTensor> = HashMap::new();
+
+    for (name, info) in entries.iter() {
+        if name == "__metadata__" {
+            if let JsonValue::Object(items) = info {
+                for (key, value) in items {
+                    let value = match value.as_str() {
+                        Some(s) => s.to_string(),
+                        None => value.to_json_string(),
+                    };
+                    metadata.push((key.clone(), value));
+                }
+            }
+            continue;
+        }
+
+        if info.get("dtype").and_then(|d| d.as_str()) != Some("F32") {
+            return Err(bad_file(&format!("tensor {} is not F32", name)));
+        }
+
+        let shape: Vec<usize> = info
+            .get("shape")
+            .and_then(|s| s.as_array())
+            .ok_or_else(|| bad_file(&format!("tensor {} has no shape", name)))?
+            .iter()
+            .map(as_index)
+            .collect::<Option<_>>()
+            .ok_or_else(|| bad_file(&format!("tensor {} has a bad shape", name)))?;
+
+        let offsets: Vec<usize> = info
+            .get("data_offsets")
+            .and_then(|o| o.as_array())
+            .and_then(|o| o.iter().map(as_index).collect())
+            .ok_or_else(|| bad_file(&format!("tensor {} has bad data_offsets", name)))?;
+
+        let num_bytes = shape
+            .iter()
+            .try_fold(4usize, |product, dim| product.checked_mul(*dim));
+        if offsets.len() != 2
+            || offsets[1] > data.len()
+            || offsets[0] > offsets[1]
+            || Some(offsets[1] - offsets[0]) != num_bytes
+        {
+            return Err(bad_file(&format!(
+                "tensor {} data doesn't match its shape",
+                name
+            )));
+        }
+
+        let vals = data[offsets[0]..offsets[1]]
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        tensors.insert(name.clone(), SafeTensor { shape, vals });
+    }
+
+    Ok(SafeTensorsFile { metadata, tensors })
+}
+
+// Shapes and offsets have to be non-negative whole numbers.
+fn as_index(value: &JsonValue) -> Option<usize> {
+    let n = value.as_f64()?;
+    (n.fract() == 0.0 && n >= 0.0 && n <= usize::MAX as f64).then_some(n as usize)
+}
+
+// Copies tensors from a safetensors file into the matching model parameters.
+// Vectors may be stored either as `[n]` or `[1, n]`.
+pub fn load_safetensors(filename: &str, model: &mut Model) -> Result<(), ErrorE> {
+    let mut tensors = read_safetensors(filename)?.tensors;
+
+    for param in model.params_mut() {
+        let Some(tensor) = tensors.remove(&param.name) else {
+            return Err(ErrorE::ErrorCheckpointFormat(format!(
+                "{} is missing tensor {}",
+                filename, param.name
+            )));
+        };
+
+        let expected = [param.rows as usize, param.cols as usize];
+        let shape_matches =
+            tensor.shape == expected || (param.rows == 1 && tensor.shape == [param.cols as usize]);
+        if !shape_matches {
+            return Err(ErrorE::ErrorCheckpointFormat(format!(
+                "Tensor {} has shape {:?} but the model expects {:?}",
+                param.name, tensor.shape, expected
+            )));
+        }
+
+        param.vals.copy_from_slice(&tensor.vals);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{temp_path, test_model};
+
+    #[test]
+    fn save_then_load_restores_every_tensor() {
+        let model = test_model(0);
+        let path = temp_path("roundtrip.safetensors");
+        save_safetensors(&path, &model).unwrap();
+
+        let file = read_safetensors(&path);
+        let mut loaded = test_model(1);
+        let result = load_safetensors(&path, &mut loaded);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        let file = file.unwrap();
+        assert!(
+            file.metadata
+                .contains(&("dim".to_string(), "8".to_string()))
+        );
+        assert!(
+            file.metadata
+                .contains(&("feed_forward".to_string(), "mlp".to_string()))
+        );
+        assert_eq!(file.tensors["w_o"].shape, [8, 20]);
+        for (loaded, saved) in loaded.params().iter().zip(model.params().iter()) {
+            assert_eq!(loaded.name, saved.name);
+            assert_eq!(loaded.vals, saved.vals, "{}", saved.name);
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use crate::{config::ModelConfig, model::Model, utils::seed_rng};
+
+// The small model most tests share: two blocks of dim 8 with two heads,
+// seq_len 6 and a vocab of 20. `seed` fixes its weights.
+pub fn test_model(seed: u64) -> Model {
+    seed_rng(seed);
+    Model::new(&ModelConfig {
+        seq_len: 6,
+        dim: 8,
+        vocab_size: 20,
+        num_transformers: 2,
+        n_heads: 2,
+        ..ModelConfig::default()
+    })
+}
+
+// A file in the temp dir that's unique to this process, so concurrent test
+// runs don't overwrite each other's files.
+pub fn temp_path(file_name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("tinygpt-{}-{}", std::process::id(), file_name))
+        .to_str()
+        .unwrap()
+        .to_string()
+}
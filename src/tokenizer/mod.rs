@@ -1,334 +1,1197 @@
 use std::{
-    cell::RefCell,
-    collections::{HashMap, HashSet},
-    hash::{Hash, Hasher},
-    rc::Rc,
-    usize,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    fs,
+    sync::Mutex,
 };
 
-#[derive(Clone)]
-pub struct Token {
-    pub id: i32,
-    pub val: String,
-    occurrences: HashSet<DLLTokenRef>,
+use crate::{error::ErrorE, pretokenizer::PreTokenizerE, utils::read_file};
+
+const TOKENIZER_FILE_HEADER: &str = "tinygpt-tokenizer 4";
+
+// The encode cache is dropped once it holds this many chunks.
+const ENCODE_CACHE_MAX_ENTRIES: usize = 1 << 16;
+
+// Ids 0..256 are the single bytes, then come the special tokens (BOS, EOS,
+// PAD and UNK at fixed ids, then any user-defined ones), then merged tokens.
+pub const NUM_BYTE_TOKENS: usize = 256;
+
+pub const BOS_TOKEN: &str = "<|bos|>";
+pub const EOS_TOKEN: &str = "<|eos|>";
+pub const PAD_TOKEN: &str = "<|pad|>";
+pub const UNK_TOKEN: &str = "<|unk|>";
+pub const BOS_ID: u32 = 256;
+pub const EOS_ID: u32 = 257;
+pub const PAD_ID: u32 = 258;
+pub const UNK_ID: u32 = 259;
+pub const BUILTIN_SPECIAL_TOKENS: [&str; 4] = [BOS_TOKEN, EOS_TOKEN, PAD_TOKEN, UNK_TOKEN];
+
+#[derive(Clone, Copy)]
+pub struct MergeRule {
+    pub left: u32,
+    pub right: u32,
+    pub merged: u32,
+}
+
+pub struct Tokenizer {
+    // Token bytes indexed by token id. Merged tokens can end partway
+    // through a UTF-8 character. Read-only from outside, like `merges`,
+    // since `merge_ranks` and `special_tokens` are built from them.
+    vocab: Vec<Vec<u8>>,
+    // Merge rules in the order they were learned.
+    merges: Vec<MergeRule>,
+    pub pre_tokenizer: PreTokenizerE,
+    // Whether `encode` turns special token text such as `<|eos|>` into the
+    // special token. Off by default so arbitrary input can't inject them.
+    pub parse_special_tokens: bool,
+    // (text, id) of each special token, in id order.
+    special_tokens: Vec<(String, u32)>,
+    merge_ranks: HashMap<(u32, u32), usize>,
+    // Encodings of chunks seen so far, keyed by chunk text.
+    encode_cache: Mutex<HashMap<String, Vec<u32>>>,
 }
 
-impl Token {
-    fn new(id: i32, val: String) -> Self {
-        Token {
-            id,
-            val,
-            occurrences: HashSet::new(),
+impl Tokenizer {
+    // `vocab` has to start with the 256 single-byte tokens in byte order,
+    // followed by `num_special_tokens` special tokens (none, or the builtin
+    // four and then any user-defined ones).
+    pub fn new(
+        vocab: Vec<Vec<u8>>,
+        merges: Vec<MergeRule>,
+        pre_tokenizer: PreTokenizerE,
+        num_special_tokens: usize,
+    ) -> Result<Self, ErrorE> {
+        check_vocab(&vocab, num_special_tokens).map_err(ErrorE::ErrorTokenizer)?;
+
+        Ok(Tokenizer::build(
+            vocab,
+            merges,
+            pre_tokenizer,
+            num_special_tokens,
+            false,
+        ))
+    }
+
+    fn build(
+        vocab: Vec<Vec<u8>>,
+        merges: Vec<MergeRule>,
+        pre_tokenizer: PreTokenizerE,
+        num_special_tokens: usize,
+        parse_special_tokens: bool,
+    ) -> Self {
+        let merge_ranks = merges
+            .iter()
+            .enumerate()
+            .map(|(rank, rule)| ((rule.left, rule.right), rank))
+            .collect();
+        let special_tokens = (NUM_BYTE_TOKENS..NUM_BYTE_TOKENS + num_special_tokens)
+            .map(|id| (String::from_utf8_lossy(&vocab[id]).into_owned(), id as u32))
+            .collect();
+
+        Self {
+            vocab,
+            merges,
+            pre_tokenizer,
+            parse_special_tokens,
+            special_tokens,
+            merge_ranks,
+            encode_cache: Mutex::new(HashMap::new()),
         }
     }
-}
 
-impl PartialEq for Token {
-    fn eq(&self, other: &Self) -> bool {
-        self.id == other.id
+    pub fn vocab_size(self: &Tokenizer) -> usize {
+        self.vocab.len()
     }
-}
 
-impl Eq for Token {}
+    pub fn vocab(self: &Tokenizer) -> &[Vec<u8>] {
+        &self.vocab
+    }
 
-impl Hash for Token {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.id.hash(state);
+    pub fn merges(self: &Tokenizer) -> &[MergeRule] {
+        &self.merges
     }
-}
 
-#[derive(Clone)]
-pub struct TokenRef(pub Rc<RefCell<Token>>);
+    pub fn special_tokens(self: &Tokenizer) -> &[(String, u32)] {
+        &self.special_tokens
+    }
 
-impl PartialEq for TokenRef {
-    fn eq(&self, other: &Self) -> bool {
-        self.0.borrow().id == other.0.borrow().id
+    pub fn special_token_id(self: &Tokenizer, text: &str) -> Option<u32> {
+        self.special_tokens
+            .iter()
+            .find(|(val, _)| val == text)
+            .map(|(_, id)| *id)
     }
-}
 
-impl Eq for TokenRef {}
+    pub fn is_special(self: &Tokenizer, id: u32) -> bool {
+        self.special_tokens
+            .iter()
+            .any(|(_, special)| *special == id)
+    }
 
-impl Hash for TokenRef {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.0.borrow().id.hash(state);
+    // The builtin special tokens, for tokenizers that have them.
+    pub fn bos_id(self: &Tokenizer) -> Option<u32> {
+        self.is_special(BOS_ID).then_some(BOS_ID)
     }
-}
 
-#[derive(Clone)]
-pub struct DLLToken {
-    pub id: i32,
-    pub token: Rc<RefCell<Token>>,
-    pub embed: Vec<f32>,
-    pub next: Option<Rc<RefCell<DLLToken>>>,
-    pub prev: Option<Rc<RefCell<DLLToken>>>,
-}
+    pub fn eos_id(self: &Tokenizer) -> Option<u32> {
+        self.is_special(EOS_ID).then_some(EOS_ID)
+    }
 
-impl PartialEq for DLLToken {
-    fn eq(&self, other: &Self) -> bool {
-        self.id == other.id
+    pub fn pad_id(self: &Tokenizer) -> Option<u32> {
+        self.is_special(PAD_ID).then_some(PAD_ID)
     }
-}
 
-impl Eq for DLLToken {}
+    pub fn unk_id(self: &Tokenizer) -> Option<u32> {
+        self.is_special(UNK_ID).then_some(UNK_ID)
+    }
+
+    // Splits `text` into pre-tokenizer chunks, then each chunk into bytes,
+    // and replays the learned merges. Any string can be encoded and
+    // `decode(encode(s)) == s`. With `parse_special_tokens` set, special
+    // token text is cut out first and becomes the special token's id.
+    pub fn encode(self: &Tokenizer, text: &str) -> Vec<u32> {
+        if !self.parse_special_tokens {
+            return self.encode_ordinary(text);
+        }
+
+        let mut ids: Vec<u32> = vec![];
+        for (segment, special) in split_on_special_tokens(text, &self.special_tokens) {
+            match special {
+                Some(id) => ids.push(id),
+                None => ids.extend(self.encode_ordinary(segment)),
+            }
+        }
+        ids
+    }
 
-impl Hash for DLLToken {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.id.hash(state);
+    fn encode_ordinary(self: &Tokenizer, text: &str) -> Vec<u32> {
+        if self.pre_tokenizer == PreTokenizerE::PreTokenizerNone {
+            return self.apply_merges(text.bytes().map(u32::from).collect());
+        }
+
+        let mut cache = self
+            .encode_cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut ids: Vec<u32> = Vec::with_capacity(text.len());
+        for chunk in self.pre_tokenizer.split(text) {
+            if let Some(chunk_ids) = cache.get(chunk) {
+                ids.extend_from_slice(chunk_ids);
+                continue;
+            }
+
+            let chunk_ids = self.apply_merges(chunk.bytes().map(u32::from).collect());
+            ids.extend_from_slice(&chunk_ids);
+            if cache.len() >= ENCODE_CACHE_MAX_ENTRIES {
+                cache.clear();
+            }
+            cache.insert(chunk.to_string(), chunk_ids);
+        }
+
+        ids
     }
-}
 
-#[derive(Clone)]
-pub struct DLLTokenRef(Rc<RefCell<DLLToken>>);
+    // Replays the learned merges in order, always applying the
+    // earliest-learned merge present in the sequence, leftmost first.
+    //
+    // The symbols form a linked list like `BpeTrainer`'s, and a min-heap of
+    // (rank, position) holds every adjacent pair that has a merge. A merge
+    // only pushes the two pairs it creates, so each costs a heap operation
+    // rather than a pass over the sequence. Entries go stale when a neighbour
+    // is merged away and are skipped when popped. The pairs a merge creates
+    // contain its new token, so their ranks are always later than its own.
+    fn apply_merges(self: &Tokenizer, mut ids: Vec<u32>) -> Vec<u32> {
+        let len = ids.len();
+        let mut prev: Vec<usize> = (0..len)
+            .map(|i| if i > 0 { i - 1 } else { NO_SYMBOL })
+            .collect();
+        let mut next: Vec<usize> = (0..len)
+            .map(|i| if i + 1 < len { i + 1 } else { NO_SYMBOL })
+            .collect();
+        let rank_at = |ids: &[u32], next: &[usize], i: usize| match next[i] {
+            NO_SYMBOL => None,
+            j => self.merge_ranks.get(&(ids[i], ids[j])).copied(),
+        };
 
-impl PartialEq for DLLTokenRef {
-    fn eq(&self, other: &Self) -> bool {
-        self.0.borrow().id == other.0.borrow().id
+        let mut heap: BinaryHeap<Reverse<(usize, usize)>> = (0..len)
+            .filter_map(|i| rank_at(&ids, &next, i).map(|rank| Reverse((rank, i))))
+            .collect();
+        while let Some(Reverse((rank, i))) = heap.pop() {
+            if ids[i] == MERGED_SYMBOL || rank_at(&ids, &next, i) != Some(rank) {
+                continue;
+            }
+
+            let j = next[i];
+            let n = next[j];
+            ids[i] = self.merges[rank].merged;
+            ids[j] = MERGED_SYMBOL;
+            next[i] = n;
+            if n != NO_SYMBOL {
+                prev[n] = i;
+            }
+
+            for pos in [prev[i], i] {
+                if pos != NO_SYMBOL
+                    && let Some(rank) = rank_at(&ids, &next, pos)
+                {
+                    heap.push(Reverse((rank, pos)));
+                }
+            }
+        }
+
+        ids.retain(|id| *id != MERGED_SYMBOL);
+        ids
+    }
+
+    // Special tokens come out as their text. Ids outside the vocab, and
+    // bytes that don't form valid UTF-8 (neither is possible for ids that
+    // came from `encode`), come out as U+FFFD.
+    pub fn decode(self: &Tokenizer, ids: &[u32]) -> String {
+        const REPLACEMENT: &[u8] = "\u{FFFD}".as_bytes();
+        let bytes: Vec<u8> = ids
+            .iter()
+            .flat_map(|id| match self.vocab.get(*id as usize) {
+                Some(val) => val.as_slice(),
+                None => REPLACEMENT,
+            })
+            .copied()
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    // Line based format: a header, then `vocab <n>` followed by one
+    // `<id> <escaped value>` line per token, then `merges <m>` followed by one
+    // `<left> <right> <merged>` line per rule in learning order. Bytes that
+    // aren't printable UTF-8 are written as `\xHH`. The header is followed
+    // by `pre_tokenizer <name>`, `special_tokens <n>` and
+    // `parse_special_tokens <true|false>` lines.
+    pub fn save(self: &Tokenizer, filename: &str) -> Result<(), ErrorE> {
+        let mut contents = format!(
+            "{}\npre_tokenizer {}\nspecial_tokens {}\nparse_special_tokens {}\n",
+            TOKENIZER_FILE_HEADER,
+            self.pre_tokenizer.name(),
+            self.special_tokens.len(),
+            self.parse_special_tokens
+        );
+        contents.push_str(&format!("vocab {}\n", self.vocab.len()));
+        for (id, val) in self.vocab.iter().enumerate() {
+            contents.push_str(&format!("{} {}\n", id, escape_token(val)));
+        }
+
+        contents.push_str(&format!("merges {}\n", self.merges.len()));
+        for rule in self.merges.iter() {
+            contents.push_str(&format!("{} {} {}\n", rule.left, rule.right, rule.merged));
+        }
+
+        fs::write(filename, contents).map_err(|error| ErrorE::io(filename, error))
+    }
+
+    pub fn load(filename: &str) -> Result<Tokenizer, ErrorE> {
+        let contents = read_file(filename)?;
+        let bad_line =
+            |line: &str| ErrorE::ErrorTokenizer(format!("Bad tokenizer file line: {:?}", line));
+
+        let mut lines = contents.split('\n');
+        if lines.next() != Some(TOKENIZER_FILE_HEADER) {
+            return Err(ErrorE::ErrorTokenizer(format!(
+                "{} is not a tokenizer file",
+                filename
+            )));
+        }
+
+        let line = lines.next().unwrap_or("");
+        let pre_tokenizer = line
+            .strip_prefix("pre_tokenizer ")
+            .and_then(PreTokenizerE::from_name)
+            .ok_or_else(|| bad_line(line))?;
+
+        let line = lines.next().unwrap_or("");
+        let num_special_tokens: usize = line
+            .strip_prefix("special_tokens ")
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| bad_line(line))?;
+
+        let line = lines.next().unwrap_or("");
+        let parse_special_tokens: bool = line
+            .strip_prefix("parse_special_tokens ")
+            .and_then(|flag| flag.parse().ok())
+            .ok_or_else(|| bad_line(line))?;
+
+        let vocab_line = lines.next().unwrap_or("");
+        let vocab_size: usize = vocab_line
+            .strip_prefix("vocab ")
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| bad_line(vocab_line))?;
+
+        let mut vocab: Vec<Vec<u8>> = vec![];
+        for _ in 0..vocab_size {
+            let line = lines.next().unwrap_or("");
+            let (id, val) = line.split_once(' ').ok_or_else(|| bad_line(line))?;
+            if id.parse::<usize>().ok() != Some(vocab.len()) {
+                return Err(bad_line(line));
+            }
+            vocab.push(unescape_token(val).ok_or_else(|| bad_line(line))?);
+        }
+
+        let merges_line = lines.next().unwrap_or("");
+        let num_merges: usize = merges_line
+            .strip_prefix("merges ")
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| bad_line(merges_line))?;
+
+        let mut merges: Vec<MergeRule> = vec![];
+        for _ in 0..num_merges {
+            let line = lines.next().unwrap_or("");
+            let ids: Vec<u32> = line
+                .split(' ')
+                .map(|id| id.parse::<u32>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| bad_line(line))?;
+            if ids.len() != 3 || ids.iter().any(|id| *id as usize >= vocab_size) {
+                return Err(bad_line(line));
+            }
+            merges.push(MergeRule {
+                left: ids[0],
+                right: ids[1],
+                merged: ids[2],
+            });
+        }
+
+        check_vocab(&vocab, num_special_tokens)
+            .and_then(|_| check_merges(&vocab, &merges, num_special_tokens))
+            .map_err(|message| ErrorE::ErrorTokenizer(format!("{}: {}", filename, message)))?;
+        Ok(Tokenizer::build(
+            vocab,
+            merges,
+            pre_tokenizer,
+            num_special_tokens,
+            parse_special_tokens,
+        ))
     }
 }
 
-impl Eq for DLLTokenRef {}
+fn check_vocab(vocab: &[Vec<u8>], num_special_tokens: usize) -> Result<(), String> {
+    if vocab.len() < NUM_BYTE_TOKENS + num_special_tokens
+        || (0..NUM_BYTE_TOKENS).any(|b| vocab[b] != [b as u8])
+    {
+        return Err("vocab doesn't start with the 256 byte tokens".to_string());
+    }
+    if num_special_tokens == 0 {
+        return Ok(());
+    }
 
-impl Hash for DLLTokenRef {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.0.borrow().id.hash(state);
+    let specials = &vocab[NUM_BYTE_TOKENS..NUM_BYTE_TOKENS + num_special_tokens];
+    let has_builtins = specials.len() >= BUILTIN_SPECIAL_TOKENS.len()
+        && BUILTIN_SPECIAL_TOKENS
+            .iter()
+            .zip(specials)
+            .all(|(builtin, special)| builtin.as_bytes() == special.as_slice());
+    if !has_builtins {
+        return Err(format!(
+            "special tokens have to start with {}",
+            BUILTIN_SPECIAL_TOKENS.join(" ")
+        ));
+    }
+    for (i, special) in specials.iter().enumerate() {
+        if special.is_empty() || std::str::from_utf8(special).is_err() {
+            return Err(format!("special token {:?} isn't valid text", special));
+        }
+        if specials[..i].contains(special) {
+            return Err(format!(
+                "special token {} is registered twice",
+                String::from_utf8_lossy(special)
+            ));
+        }
     }
+    Ok(())
 }
 
-impl DLLToken {
-    fn new(id: i32, token: Rc<RefCell<Token>>, prev: Option<Rc<RefCell<DLLToken>>>) -> Self {
-        DLLToken {
-            id: id,
-            token,
-            embed: Vec::new(),
-            next: None,
-            prev,
+// Merge `i` has to produce token `NUM_BYTE_TOKENS + num_special_tokens + i`
+// out of two earlier non-special tokens, and the vocab can't hold anything
+// past the last merge, or `encode` and `decode` would disagree.
+fn check_merges(
+    vocab: &[Vec<u8>],
+    merges: &[MergeRule],
+    num_special_tokens: usize,
+) -> Result<(), String> {
+    let first_merged = NUM_BYTE_TOKENS + num_special_tokens;
+    if vocab.len() != first_merged + merges.len() {
+        return Err(format!(
+            "vocab has {} tokens but the bytes, {} special tokens and {} merges make {}",
+            vocab.len(),
+            num_special_tokens,
+            merges.len(),
+            first_merged + merges.len()
+        ));
+    }
+
+    let is_special = |id: usize| (NUM_BYTE_TOKENS..first_merged).contains(&id);
+    for (i, rule) in merges.iter().enumerate() {
+        let (left, right, merged) = (
+            rule.left as usize,
+            rule.right as usize,
+            rule.merged as usize,
+        );
+        if merged != first_merged + i {
+            return Err(format!(
+                "merge {} makes token {} instead of {}",
+                i,
+                merged,
+                first_merged + i
+            ));
+        }
+        if left >= merged || right >= merged || is_special(left) || is_special(right) {
+            return Err(format!(
+                "merge {} has to join two earlier tokens that aren't special",
+                i
+            ));
+        }
+        if vocab[merged] != [vocab[left].as_slice(), vocab[right].as_slice()].concat() {
+            return Err(format!(
+                "token {} isn't tokens {} and {} joined",
+                merged, left, right
+            ));
         }
     }
+    Ok(())
 }
 
-fn count_token_pairs(
-    head: Rc<RefCell<DLLToken>>,
-    token_pair_count_map: &mut HashMap<(String, String), i32>,
-) -> Option<(Rc<RefCell<Token>>, Rc<RefCell<Token>>)> {
-    let mut node = Some(head);
-    let mut max_count_token_pair: Option<(Rc<RefCell<Token>>, Rc<RefCell<Token>>)> = None;
-    let mut max_count = 0i32;
-
-    while let Some(token) = node {
-        let next_node = token.borrow().next.clone();
-
-        if let Some(next) = next_node {
-            let curr_val = token.borrow().token.borrow().val.clone();
-            let next_val = next.borrow().token.borrow().val.clone();
-            let pair = (curr_val, next_val);
-
-            *token_pair_count_map.entry(pair.clone()).or_insert(0) += 1;
-            let curr_count = token_pair_count_map.get(&pair).unwrap();
-            if *curr_count > max_count {
-                max_count_token_pair = Some((
-                    Rc::clone(&token.borrow().token),
-                    Rc::clone(&next.borrow().token),
-                ));
-
-                max_count = *curr_count;
+// Cuts `text` at every verbatim occurrence of a special token. Where two
+// special tokens start at the same place the longer one wins.
+//
+// Each token's next match is remembered and only searched for again once
+// the text before it has been consumed, so a token that doesn't appear
+// costs a single scan.
+fn split_on_special_tokens<'a>(
+    text: &'a str,
+    special_tokens: &[(String, u32)],
+) -> Vec<(&'a str, Option<u32>)> {
+    let mut segments: Vec<(&str, Option<u32>)> = vec![];
+    let mut next_matches: Vec<Option<usize>> = special_tokens
+        .iter()
+        .map(|(val, _)| text.find(val.as_str()))
+        .collect();
+    let mut start = 0usize;
+
+    loop {
+        for ((val, _), next_match) in special_tokens.iter().zip(next_matches.iter_mut()) {
+            if next_match.is_some_and(|pos| pos < start) {
+                *next_match = text[start..].find(val.as_str()).map(|pos| start + pos);
             }
+        }
 
-            node = Some(next);
-        } else {
+        let next = special_tokens
+            .iter()
+            .zip(next_matches.iter())
+            .filter_map(|((val, id), next_match)| {
+                next_match.map(|pos| (pos, Reverse(val.len()), *id))
+            })
+            .min();
+        let Some((pos, Reverse(len), id)) = next else {
             break;
+        };
+
+        if pos > start {
+            segments.push((&text[start..pos], None));
         }
+        segments.push((&text[pos..pos + len], Some(id)));
+        start = pos + len;
     }
 
-    max_count_token_pair
+    if start < text.len() {
+        segments.push((&text[start..], None));
+    }
+    segments
 }
 
-fn merge_tokens(
-    token_a: Rc<RefCell<Token>>,
-    token_b: Rc<RefCell<Token>>,
-    token_pair_count_map: &mut HashMap<(String, String), i32>,
-    num_tokens: &mut i32,
-    num_dll_tokens: &mut i32,
-) -> Option<Rc<RefCell<DLLToken>>> {
-    let token_a_val = &token_a.borrow().val;
-    let token_b_val = &token_b.borrow().val;
-    let merge_pair = (token_a_val.clone(), token_b_val.clone());
-    let merged_token_val = format!("{}{}", token_a_val, token_b_val);
-
-    let merged_token = Rc::new(RefCell::new(Token::new(
-        *num_tokens,
-        merged_token_val.clone(),
-    )));
-    *num_tokens += 1;
-
-    let mut new_head: Option<Rc<RefCell<DLLToken>>> = None;
-    for dll_token in token_a.borrow().occurrences.iter() {
-        let borrowed_dll_token = dll_token.0.borrow();
-        if borrowed_dll_token.next.is_none() {
-            continue;
+fn escape_token(val: &[u8]) -> String {
+    let mut escaped = String::with_capacity(val.len());
+    for chunk in val.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                '\t' => escaped.push_str("\\t"),
+                _ if c.is_ascii_control() => escaped.push_str(&format!("\\x{:02x}", c as u8)),
+                _ => escaped.push(c),
+            }
         }
+        for b in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    escaped
+}
 
-        let next_dll_token = borrowed_dll_token.next.as_ref().unwrap();
-        if next_dll_token.borrow().token.borrow().id != token_b.borrow().id {
+fn unescape_token(val: &str) -> Option<Vec<u8>> {
+    let mut unescaped: Vec<u8> = Vec::with_capacity(val.len());
+    let mut chars = val.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0u8; 4];
+            unescaped.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
             continue;
         }
+        match chars.next() {
+            Some('n') => unescaped.push(b'\n'),
+            Some('r') => unescaped.push(b'\r'),
+            Some('t') => unescaped.push(b'\t'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                unescaped.push(u8::from_str_radix(&hex, 16).ok()?);
+            }
+            Some(other) => {
+                let mut buf = [0u8; 4];
+                unescaped.extend_from_slice(other.encode_utf8(&mut buf).as_bytes());
+            }
+            None => unescaped.push(b'\\'),
+        }
+    }
+    Some(unescaped)
+}
 
-        let merged_dll_token = Rc::new(RefCell::new(DLLToken::new(
-            *num_dll_tokens,
-            Rc::clone(&merged_token),
-            None,
-        )));
-
-        merged_token
-            .borrow_mut()
-            .occurrences
-            .insert(DLLTokenRef(Rc::clone(&merged_dll_token)));
-
-        *num_dll_tokens += 1;
-
-        // Reduce the count of (a, b)
-        token_pair_count_map
-            .entry(merge_pair.clone())
-            .and_modify(|count| *count -= 1);
-
-        // Reduce the count of (prev_a, a)
-        if let Some(prev) = &dll_token.0.borrow().prev {
-            let prev_val = prev.borrow().token.borrow().val.clone();
-            token_pair_count_map
-                .entry((prev_val.clone(), token_a_val.clone()))
-                .and_modify(|count| *count -= 1);
-            token_pair_count_map
-                .entry((prev_val.clone(), merged_token_val.clone()))
-                .and_modify(|count| *count += 1)
-                .or_insert(1);
-            merged_dll_token.borrow_mut().prev = Some(Rc::clone(&prev));
-            prev.borrow_mut().next = Some(Rc::clone(&merged_dll_token));
-        } else {
-            // If the first pair got merged
-            new_head = Some(Rc::clone(&merged_dll_token));
+pub struct BpeTrainerConfig {
+    // Merging stops once the vocab (the 256 byte tokens, the special tokens
+    // and the merged tokens) reaches this size.
+    pub vocab_size: usize,
+    // Pairs seen fewer times than this are never merged.
+    pub min_pair_frequency: usize,
+    // Longest merged token, in bytes. 0 means no limit.
+    pub max_token_len: usize,
+    pub pre_tokenizer: PreTokenizerE,
+    // User-defined special tokens, given ids after BOS, EOS, PAD and UNK.
+    pub special_tokens: Vec<String>,
+    // Treat special token text in the training text as the special token.
+    pub parse_special_tokens: bool,
+}
+
+impl Default for BpeTrainerConfig {
+    fn default() -> Self {
+        Self {
+            vocab_size: 512,
+            min_pair_frequency: 2,
+            max_token_len: 16,
+            pre_tokenizer: PreTokenizerE::PreTokenizerGPT2,
+            special_tokens: vec![],
+            parse_special_tokens: false,
         }
+    }
+}
+
+// Marks a missing neighbour in `BpeTrainer::prev`/`next`, and in the lists
+// `Tokenizer::apply_merges` builds.
+const NO_SYMBOL: usize = usize::MAX;
+// Id left behind by a symbol that was merged into its left neighbour.
+const MERGED_SYMBOL: u32 = u32::MAX;
+
+type TokenPair = (u32, u32);
+
+// Byte pair encoding over the training text kept as a linked list of
+// symbols, with a break in the list between pre-tokenizer chunks. Every
+// adjacent pair's count is kept exact as merges rewrite the list, and a
+// max-heap of (count, pair) picks the next merge, so a merge only touches
+// the places where its pair occurs. Heap entries go stale when a count
+// changes; they're skipped when popped and a fresh one is pushed for every
+// changed count.
+//
+// Ties on count go to the pair with the smallest ids, which makes training
+// deterministic.
+struct BpeTrainer {
+    // Token id of each symbol, indexed by its position in the original text.
+    // A merge keeps the left symbol and retires the right one.
+    ids: Vec<u32>,
+    prev: Vec<usize>,
+    next: Vec<usize>,
+    pair_counts: HashMap<TokenPair, i64>,
+    // Where each pair's left symbol was when the pair was formed. Entries
+    // are checked before use since later merges can invalidate them.
+    pair_positions: HashMap<TokenPair, Vec<usize>>,
+    heap: BinaryHeap<(i64, Reverse<TokenPair>)>,
+}
+
+impl BpeTrainer {
+    // `chunk_lens` splits `ids` into runs that are never merged across.
+    fn new(ids: Vec<u32>, chunk_lens: &[usize]) -> Self {
+        let len = ids.len();
+        let mut trainer = BpeTrainer {
+            prev: (0..len)
+                .map(|i| if i > 0 { i - 1 } else { NO_SYMBOL })
+                .collect(),
+            next: (0..len)
+                .map(|i| if i + 1 < len { i + 1 } else { NO_SYMBOL })
+                .collect(),
+            ids,
+            pair_counts: HashMap::new(),
+            pair_positions: HashMap::new(),
+            heap: BinaryHeap::new(),
+        };
 
-        // Reduce the count of (b, next_b)
-        if let Some(next) = &next_dll_token.borrow().next {
-            let next_val = next.borrow().token.borrow().val.clone();
-            token_pair_count_map
-                .entry((token_b_val.clone(), next_val.clone()))
-                .and_modify(|count| *count -= 1);
-            token_pair_count_map
-                .entry((merged_token_val.clone(), next_val.clone()))
-                .and_modify(|count| *count += 1)
-                .or_insert(1);
-            merged_dll_token.borrow_mut().next = Some(Rc::clone(&next));
-            next.borrow_mut().prev = Some(Rc::clone(&merged_dll_token));
+        let mut chunk_start = 0usize;
+        for chunk_len in chunk_lens {
+            let chunk_end = chunk_start + chunk_len;
+            if chunk_start > 0 {
+                trainer.prev[chunk_start] = NO_SYMBOL;
+                trainer.next[chunk_start - 1] = NO_SYMBOL;
+            }
+
+            for i in chunk_start + 1..chunk_end {
+                let pair = (trainer.ids[i - 1], trainer.ids[i]);
+                *trainer.pair_counts.entry(pair).or_insert(0) += 1;
+                trainer.pair_positions.entry(pair).or_default().push(i - 1);
+            }
+            chunk_start = chunk_end;
         }
+        for (pair, count) in trainer.pair_counts.iter() {
+            trainer.heap.push((*count, Reverse(*pair)));
+        }
+
+        trainer
     }
 
-    return new_head;
+    // The most frequent pair, or None once no pair is left.
+    fn pop_best_pair(self: &mut BpeTrainer) -> Option<(TokenPair, i64)> {
+        while let Some((count, Reverse(pair))) = self.heap.pop() {
+            if self.pair_counts.get(&pair) == Some(&count) && count > 0 {
+                return Some((pair, count));
+            }
+        }
+        None
+    }
+
+    fn add_pair_count(
+        self: &mut BpeTrainer,
+        pair: TokenPair,
+        delta: i64,
+        changed: &mut Vec<TokenPair>,
+    ) {
+        *self.pair_counts.entry(pair).or_insert(0) += delta;
+        changed.push(pair);
+    }
+
+    // Replaces every non-overlapping occurrence of `pair`, left to right,
+    // with `merged`.
+    fn merge(self: &mut BpeTrainer, pair: TokenPair, merged: u32) {
+        let (left, right) = pair;
+        let mut positions = self.pair_positions.remove(&pair).unwrap_or_default();
+        positions.sort_unstable();
+        positions.dedup();
+
+        let mut changed: Vec<TokenPair> = vec![];
+        for i in positions {
+            let j = self.next[i];
+            if self.ids[i] != left || j == NO_SYMBOL || self.ids[j] != right {
+                continue;
+            }
+
+            self.add_pair_count(pair, -1, &mut changed);
+
+            let p = self.prev[i];
+            if p != NO_SYMBOL {
+                let prev_id = self.ids[p];
+                self.add_pair_count((prev_id, left), -1, &mut changed);
+                self.add_pair_count((prev_id, merged), 1, &mut changed);
+                self.pair_positions
+                    .entry((prev_id, merged))
+                    .or_default()
+                    .push(p);
+            }
+
+            let n = self.next[j];
+            if n != NO_SYMBOL {
+                let next_id = self.ids[n];
+                self.add_pair_count((right, next_id), -1, &mut changed);
+                self.add_pair_count((merged, next_id), 1, &mut changed);
+                self.pair_positions
+                    .entry((merged, next_id))
+                    .or_default()
+                    .push(i);
+                self.prev[n] = i;
+            }
+
+            self.ids[i] = merged;
+            self.next[i] = n;
+            self.ids[j] = MERGED_SYMBOL;
+        }
+
+        changed.sort_unstable();
+        changed.dedup();
+        for pair in changed {
+            let count = self.pair_counts[&pair];
+            if count > 0 {
+                self.heap.push((count, Reverse(pair)));
+            } else {
+                self.pair_counts.remove(&pair);
+                self.pair_positions.remove(&pair);
+            }
+        }
+    }
+
+    fn token_ids(self: &BpeTrainer) -> Vec<u32> {
+        self.ids
+            .iter()
+            .copied()
+            .filter(|id| *id != MERGED_SYMBOL)
+            .collect()
+    }
 }
 
-fn init_tokens(
+// Trains byte-level BPE merges on `sequence` and returns the tokenizer
+// together with the token ids of the training text. Special tokens get their
+// ids up front and are never merged; an empty, repeated or builtin one in
+// `config.special_tokens` is an error.
+pub fn tokenizer(
     sequence: String,
-    token_map: &mut HashMap<String, Rc<RefCell<Token>>>,
-    num_tokens: &mut i32,
-    tokens: &mut HashSet<TokenRef>,
-) -> (i32, Option<Rc<RefCell<DLLToken>>>) {
-    let mut num_dll_tokens = 0i32;
-    let mut prev: Option<Rc<RefCell<DLLToken>>> = None;
-    let mut head: Option<Rc<RefCell<DLLToken>>> = None;
-
-    for c in sequence.chars() {
-        let token = token_map.entry(c.to_string()).or_insert_with(|| {
-            let token = Rc::new(RefCell::new(Token::new(*num_tokens, c.to_string())));
-            tokens.insert(TokenRef(Rc::clone(&token)));
-            *num_tokens += 1;
-            token
-        });
-
-        let dll_token = if let Some(prev_token) = &prev {
-            let new_dll_token = Rc::new(RefCell::new(DLLToken::new(
-                num_dll_tokens,
-                Rc::clone(&token),
-                Some(Rc::clone(prev_token)),
-            )));
+    config: &BpeTrainerConfig,
+) -> Result<(Tokenizer, Vec<u32>), ErrorE> {
+    let mut vocab: Vec<Vec<u8>> = (0..=u8::MAX).map(|b| vec![b]).collect();
+    let special_tokens: Vec<(String, u32)> = BUILTIN_SPECIAL_TOKENS
+        .iter()
+        .map(|val| val.to_string())
+        .chain(config.special_tokens.iter().cloned())
+        .enumerate()
+        .map(|(i, val)| (val, (NUM_BYTE_TOKENS + i) as u32))
+        .collect();
+    for (val, _) in special_tokens.iter() {
+        vocab.push(val.as_bytes().to_vec());
+    }
+    check_vocab(&vocab, special_tokens.len()).map_err(ErrorE::ErrorTokenizer)?;
 
-            prev_token.borrow_mut().next = Some(Rc::clone(&new_dll_token));
-            Rc::clone(&new_dll_token)
-        } else {
-            head = Some(Rc::new(RefCell::new(DLLToken::new(
-                num_dll_tokens,
-                Rc::clone(&token),
-                None,
-            ))));
-            head.clone().unwrap()
+    let segments = if config.parse_special_tokens {
+        split_on_special_tokens(&sequence, &special_tokens)
+    } else {
+        vec![(sequence.as_str(), None)]
+    };
+
+    // A special token is a chunk of its own, so it never pairs up.
+    let mut ids: Vec<u32> = Vec::with_capacity(sequence.len());
+    let mut chunk_lens: Vec<usize> = vec![];
+    for (segment, special) in segments {
+        match special {
+            Some(id) => {
+                ids.push(id);
+                chunk_lens.push(1);
+            }
+            None => {
+                ids.extend(segment.bytes().map(u32::from));
+                chunk_lens.extend(
+                    config
+                        .pre_tokenizer
+                        .split(segment)
+                        .iter()
+                        .map(|chunk| chunk.len()),
+                );
+            }
+        }
+    }
+    let mut trainer = BpeTrainer::new(ids, &chunk_lens);
+    let mut merges: Vec<MergeRule> = vec![];
+
+    while vocab.len() < config.vocab_size {
+        let Some(((left, right), count)) = trainer.pop_best_pair() else {
+            break;
         };
+        if (count as usize) < config.min_pair_frequency {
+            break;
+        }
 
-        num_dll_tokens += 1;
+        // Too long now means too long forever; the pair only comes back if
+        // its count changes, and is skipped again then.
+        let merged_len = vocab[left as usize].len() + vocab[right as usize].len();
+        if config.max_token_len > 0 && merged_len > config.max_token_len {
+            continue;
+        }
 
-        token
-            .borrow_mut()
-            .occurrences
-            .insert(DLLTokenRef(Rc::clone(&dll_token)));
+        let rule = MergeRule {
+            left,
+            right,
+            merged: vocab.len() as u32,
+        };
+        trainer.merge((left, right), rule.merged);
 
-        prev = Some(Rc::clone(&dll_token));
+        vocab.push([vocab[left as usize].as_slice(), &vocab[right as usize]].concat());
+        merges.push(rule);
     }
 
-    return (num_dll_tokens, head);
+    let mut tokenizer = Tokenizer::new(vocab, merges, config.pre_tokenizer, special_tokens.len())?;
+    tokenizer.parse_special_tokens = config.parse_special_tokens;
+    Ok((tokenizer, trainer.token_ids()))
 }
 
-pub fn tokenizer(sequence: String) -> (HashSet<TokenRef>, Option<Rc<RefCell<DLLToken>>>, Vec<i32>) {
-    let mut tokens: HashSet<TokenRef> = HashSet::new();
-    let mut token_map: HashMap<String, Rc<RefCell<Token>>> = HashMap::new();
-    let mut num_tokens = 0i32;
-    let mut token_pair_count_map: HashMap<(String, String), i32> = HashMap::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_path;
+
+    const SAMPLE: &str = include_str!("../../assets/sample.txt");
 
-    let (mut num_dll_tokens, head) =
-        init_tokens(sequence, &mut token_map, &mut num_tokens, &mut tokens);
+    fn merge_triples(merges: &[MergeRule]) -> Vec<(u32, u32, u32)> {
+        merges
+            .iter()
+            .map(|rule| (rule.left, rule.right, rule.merged))
+            .collect()
+    }
+
+    // Reference BPE: recount every overlapping pair from scratch before each
+    // merge, then rewrite each chunk left to right.
+    fn naive_bpe(text: &str, config: &BpeTrainerConfig) -> (Vec<(u32, u32, u32)>, Vec<u32>) {
+        let mut token_lens: Vec<usize> = vec![1; NUM_BYTE_TOKENS];
+        let mut specials: Vec<(String, u32)> = vec![];
+        for (i, val) in BUILTIN_SPECIAL_TOKENS
+            .iter()
+            .map(|val| val.to_string())
+            .chain(config.special_tokens.iter().cloned())
+            .enumerate()
+        {
+            token_lens.push(val.len());
+            specials.push((val, (NUM_BYTE_TOKENS + i) as u32));
+        }
 
-    if head.is_none() {
-        eprintln!("[tokenizer] No head found");
-        return (tokens, None, vec![]);
+        let segments = if config.parse_special_tokens {
+            split_on_special_tokens(text, &specials)
+        } else {
+            vec![(text, None)]
+        };
+        let mut chunks: Vec<Vec<u32>> = vec![];
+        for (segment, special) in segments {
+            match special {
+                Some(id) => chunks.push(vec![id]),
+                None => chunks.extend(
+                    config
+                        .pre_tokenizer
+                        .split(segment)
+                        .iter()
+                        .map(|chunk| chunk.bytes().map(u32::from).collect()),
+                ),
+            }
+        }
+
+        let mut merges: Vec<(u32, u32, u32)> = vec![];
+        while token_lens.len() < config.vocab_size {
+            let mut counts: HashMap<TokenPair, usize> = HashMap::new();
+            for chunk in chunks.iter() {
+                for pair in chunk.windows(2) {
+                    *counts.entry((pair[0], pair[1])).or_insert(0) += 1;
+                }
+            }
+            let best = counts
+                .into_iter()
+                .filter(|((left, right), _)| {
+                    config.max_token_len == 0
+                        || token_lens[*left as usize] + token_lens[*right as usize]
+                            <= config.max_token_len
+                })
+                .max_by_key(|(pair, count)| (*count, Reverse(*pair)));
+            let Some(((left, right), count)) = best else {
+                break;
+            };
+            if count < config.min_pair_frequency {
+                break;
+            }
+
+            let merged = token_lens.len() as u32;
+            for chunk in chunks.iter_mut() {
+                let mut rewritten: Vec<u32> = vec![];
+                let mut i = 0usize;
+                while i < chunk.len() {
+                    if i + 1 < chunk.len() && chunk[i] == left && chunk[i + 1] == right {
+                        rewritten.push(merged);
+                        i += 2;
+                    } else {
+                        rewritten.push(chunk[i]);
+                        i += 1;
+                    }
+                }
+                *chunk = rewritten;
+            }
+            token_lens.push(token_lens[left as usize] + token_lens[right as usize]);
+            merges.push((left, right, merged));
+        }
+
+        (merges, chunks.concat())
     }
 
-    let mut dll_head = head.unwrap();
+    #[test]
+    fn trainer_matches_naive_bpe() {
+        let texts = [
+            SAMPLE,
+            "aaaa",
+            "aaaaa",
+            "\n\n\n",
+            "\n\n\n\n\n\n\n",
+            "aaaaaaa bbbb aaaa\n\n\n\n\nabababab aaa  aa",
+            "<|eos|>aaaa<|eos|>aaa <|sep|>aaaaa<|eos|><|eos|>",
+        ];
+        let pre_tokenizers = [
+            PreTokenizerE::PreTokenizerNone,
+            PreTokenizerE::PreTokenizerGPT2,
+        ];
 
-    let max_iters = 500;
-    let mut iters = 0i32;
+        for text in texts {
+            for pre_tokenizer in pre_tokenizers {
+                for (max_token_len, min_pair_frequency) in [(0, 1), (3, 2), (16, 2), (16, 3)] {
+                    for parse_special_tokens in [false, true] {
+                        let config = BpeTrainerConfig {
+                            vocab_size: 100_000,
+                            min_pair_frequency,
+                            max_token_len,
+                            pre_tokenizer,
+                            special_tokens: vec!["<|sep|>".to_string()],
+                            parse_special_tokens,
+                        };
+                        let (tokenizer, ids) = tokenizer(text.to_string(), &config).unwrap();
+                        let (merges, naive_ids) = naive_bpe(text, &config);
+                        let setting = format!(
+                            "{:?} in {:?} with max_token_len {} and min_pair_frequency {}",
+                            text.get(..20).unwrap_or(text),
+                            pre_tokenizer,
+                            max_token_len,
+                            min_pair_frequency
+                        );
+                        assert_eq!(merge_triples(tokenizer.merges()), merges, "{}", setting);
+                        assert_eq!(ids, naive_ids, "{}", setting);
+                        // Replaying the merges has to land where training did.
+                        assert_eq!(tokenizer.encode(text), ids, "{}", setting);
+                    }
+                }
+            }
+        }
+    }
 
-    while iters < max_iters {
-        let max_pair = count_token_pairs(Rc::clone(&dll_head), &mut token_pair_count_map);
-        if max_pair.is_none() {
-            eprintln!("[tokenizer] max_pair not found");
-            break;
+    #[test]
+    fn trainer_stops_at_vocab_size() {
+        let config = BpeTrainerConfig {
+            vocab_size: NUM_BYTE_TOKENS + BUILTIN_SPECIAL_TOKENS.len() + 20,
+            ..BpeTrainerConfig::default()
+        };
+        let (tokenizer, _) = tokenizer(SAMPLE.to_string(), &config).unwrap();
+        assert_eq!(tokenizer.vocab_size(), config.vocab_size);
+        assert_eq!(
+            merge_triples(tokenizer.merges()),
+            naive_bpe(SAMPLE, &config).0
+        );
+    }
+
+    #[test]
+    fn decode_inverts_encode() {
+        let texts = [
+            "",
+            "The quick brown fox.",
+            "   \n\n\t  leading, trailing and inner   whitespace  \r\n",
+            "naïve café, Ærøskøbing, Ελληνικά, русский, 日本語のテキスト",
+            "emoji 🦀🎉 and a family 👨‍👩‍👧 with flags 🇳🇴",
+            "special text <|eos|> and <|bos|><|pad|> plus <|sep|> and <|unk",
+        ];
+
+        for pre_tokenizer in [
+            PreTokenizerE::PreTokenizerNone,
+            PreTokenizerE::PreTokenizerGPT2,
+        ] {
+            let config = BpeTrainerConfig {
+                pre_tokenizer,
+                special_tokens: vec!["<|sep|>".to_string()],
+                ..BpeTrainerConfig::default()
+            };
+            let (mut tokenizer, _) = tokenizer(SAMPLE.to_string(), &config).unwrap();
+
+            for parse_special_tokens in [false, true] {
+                tokenizer.parse_special_tokens = parse_special_tokens;
+                for text in texts {
+                    let ids = tokenizer.encode(text);
+                    assert_eq!(tokenizer.decode(&ids), text, "{:?}", pre_tokenizer);
+                    // Encoding again hits the chunk cache.
+                    assert_eq!(tokenizer.encode(text), ids);
+                }
+            }
         }
+    }
 
-        let (token_a, token_b) = max_pair.unwrap();
-        if let Some(new_head) = merge_tokens(
-            token_a,
-            token_b,
-            &mut token_pair_count_map,
-            &mut num_tokens,
-            &mut num_dll_tokens,
-        ) {
-            dll_head = Rc::clone(&new_head);
+    #[test]
+    fn special_token_text_is_only_parsed_when_enabled() {
+        let config = BpeTrainerConfig {
+            special_tokens: vec!["<|sep|>".to_string()],
+            ..BpeTrainerConfig::default()
+        };
+        let (mut tokenizer, _) = tokenizer(SAMPLE.to_string(), &config).unwrap();
+        let sep_id = tokenizer.special_token_id("<|sep|>").unwrap();
+        let text = "a<|eos|>b<|sep|>";
+
+        let ids = tokenizer.encode(text);
+        assert!(!ids.iter().any(|id| tokenizer.is_special(*id)));
+
+        tokenizer.parse_special_tokens = true;
+        let ids = tokenizer.encode(text);
+        assert_eq!(ids, vec![b'a' as u32, EOS_ID, b'b' as u32, sep_id]);
+        assert_eq!(tokenizer.decode(&ids), text);
+    }
+
+    #[test]
+    fn load_rejects_counts_past_the_end_of_the_file() {
+        let path = temp_path("counts.txt");
+        fs::write(
+            &path,
+            format!(
+                "{}\npre_tokenizer none\nspecial_tokens 0\nparse_special_tokens false\n\
+                 vocab 999999999999999999\n0 a\n",
+                TOKENIZER_FILE_HEADER
+            ),
+        )
+        .unwrap();
+        let result = Tokenizer::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(ErrorE::ErrorTokenizer(_))));
+    }
+
+    #[test]
+    fn load_rejects_merges_that_dont_build_the_vocab() {
+        // `extra` goes in the vocab after "ab" (256) and "abc" (257).
+        let load_with = |extra: &[&str], merges: &[&str]| {
+            let mut vocab: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+            vocab.push(b"ab".to_vec());
+            vocab.push(b"abc".to_vec());
+            vocab.extend(extra.iter().map(|val| val.as_bytes().to_vec()));
+
+            let mut contents = format!(
+                "{}\npre_tokenizer none\nspecial_tokens 0\nparse_special_tokens false\n\
+                 vocab {}\n",
+                TOKENIZER_FILE_HEADER,
+                vocab.len()
+            );
+            for (id, val) in vocab.iter().enumerate() {
+                contents.push_str(&format!("{} {}\n", id, escape_token(val)));
+            }
+            contents.push_str(&format!("merges {}\n", merges.len()));
+            for rule in merges {
+                contents.push_str(&format!("{}\n", rule));
+            }
+
+            let path = temp_path("merges.txt");
+            fs::write(&path, contents).unwrap();
+            let result = Tokenizer::load(&path);
+            fs::remove_file(&path).unwrap();
+            result
+        };
+
+        let loaded = load_with(&[], &["97 98 256", "256 99 257"]).unwrap();
+        assert_eq!(loaded.encode("abc"), vec![257]);
+
+        let bad_files: [(&[&str], &[&str]); 5] = [
+            // Out of order.
+            (&[], &["256 99 257", "97 98 256"]),
+            // "ba" isn't "ab".
+            (&[], &["98 97 256", "256 99 257"]),
+            // Uses a token that only comes later.
+            (&[], &["97 98 256", "257 99 257"]),
+            // Nothing makes "xyz".
+            (&["xyz"], &["97 98 256", "256 99 257"]),
+            // A merge too few.
+            (&[], &["97 98 256"]),
+        ];
+        for (extra, merges) in bad_files {
+            let result = load_with(extra, merges);
+            assert!(
+                matches!(result, Err(ErrorE::ErrorTokenizer(_))),
+                "{:?} {:?}",
+                extra,
+                merges
+            );
         }
+    }
 
-        iters += 1;
+    #[test]
+    fn special_tokens_split_leftmost_then_longest() {
+        let specials: Vec<(String, u32)> = ["ab", "abc", "bc", "zz"]
+            .iter()
+            .enumerate()
+            .map(|(i, val)| (val.to_string(), i as u32))
+            .collect();
+        assert_eq!(
+            split_on_special_tokens("xabcabab bcb", &specials),
+            vec![
+                ("x", None),
+                ("abc", Some(1)),
+                ("ab", Some(0)),
+                ("ab", Some(0)),
+                (" ", None),
+                ("bc", Some(2)),
+                ("b", None),
+            ]
+        );
     }
 
-    let mut final_tokens: HashSet<TokenRef> = HashSet::new();
-    let mut dll_node = Some(Rc::clone(&dll_head));
-    let mut token_id_map: Vec<i32> = vec![0; (num_tokens + 1) as usize];
-    let mut vocab_index = 0i32;
+    #[test]
+    fn loaded_tokenizer_keeps_parse_special_tokens() {
+        let text = format!("{}<|eos|>{}", SAMPLE, SAMPLE);
+        let config = BpeTrainerConfig {
+            parse_special_tokens: true,
+            ..BpeTrainerConfig::default()
+        };
+        let (tokenizer, ids) = tokenizer(text.clone(), &config).unwrap();
+
+        let path = temp_path("parse.txt");
+        tokenizer.save(&path).unwrap();
+        let loaded = Tokenizer::load(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert!(loaded.parse_special_tokens);
+        assert_eq!(loaded.encode(&text), ids);
+    }
 
-    for token in tokens.iter() {
-        token_id_map[token.0.borrow().id as usize] = vocab_index;
-        vocab_index += 1;
+    #[test]
+    fn decode_replaces_unknown_ids() {
+        let (tokenizer, _) = tokenizer(SAMPLE.to_string(), &BpeTrainerConfig::default()).unwrap();
+        let ids = [b'a' as u32, 100_000, b'b' as u32, u32::MAX];
+        assert_eq!(tokenizer.decode(&ids), "a\u{FFFD}b\u{FFFD}");
     }
 
-    while let Some(node_rc) = dll_node {
-        let node = node_rc.borrow();
-        final_tokens.insert(TokenRef(Rc::clone(&node.token)));
-        dll_node = node.next.clone();
+    #[test]
+    fn unescape_inverts_escape() {
+        let mut vals: Vec<Vec<u8>> = (0..=u8::MAX).map(|b| vec![b]).collect();
+        vals.extend(
+            [
+                "\\x41",
+                "a\\nb\\",
+                "tab\there\r\n",
+                "\u{7f}\u{1b}[0m",
+                "naïve 🦀",
+            ]
+            .iter()
+            .map(|val| val.as_bytes().to_vec()),
+        );
+        // A multi-byte character cut short, as merged tokens can be.
+        vals.push("é🦀".as_bytes()[..4].to_vec());
+        vals.push(vec![b'\\', 0xff, b'x']);
+
+        for val in vals {
+            let escaped = escape_token(&val);
+            assert!(!escaped.contains(['\n', '\r']), "{:?}", escaped);
+            assert_eq!(unescape_token(&escaped), Some(val));
+        }
     }
 
-    return (final_tokens, Some(dll_head), token_id_map);
+    #[test]
+    fn save_then_load_restores_the_tokenizer() {
+        let config = BpeTrainerConfig {
+            special_tokens: vec!["<|sep|>".to_string(), "<|\\x00|>".to_string()],
+            max_token_len: 0,
+            ..BpeTrainerConfig::default()
+        };
+        let (tokenizer, _) = tokenizer(SAMPLE.to_string(), &config).unwrap();
+
+        let path = temp_path("roundtrip.txt");
+        tokenizer.save(&path).unwrap();
+        let loaded = Tokenizer::load(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.vocab(), tokenizer.vocab());
+        assert_eq!(
+            merge_triples(loaded.merges()),
+            merge_triples(tokenizer.merges())
+        );
+        assert_eq!(loaded.special_tokens(), tokenizer.special_tokens());
+        assert_eq!(loaded.pre_tokenizer, tokenizer.pre_tokenizer);
+        assert!(!loaded.parse_special_tokens);
+        assert_eq!(loaded.encode(SAMPLE), tokenizer.encode(SAMPLE));
+    }
 }
@@ -0,0 +1,307 @@
+use std::time::Instant;
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::{autograd::Tape, error::ErrorE, model::Model, optimizer::Optimizer};
+
+pub struct TrainConfig {
+    pub epochs: i32,
+    pub batch_size: i32,
+    // Draw window offsets at random instead of walking the corpus in order.
+    pub random_windows: bool,
+    // Steps between calls to `train`'s progress callback; 0 never calls it.
+    pub log_every: i32,
+    // Steps between calls to `train`'s checkpoint callback; 0 never calls it.
+    pub checkpoint_every: i32,
+    pub seed: u64,
+    // Carry on from where an earlier run stopped; its seed replaces `seed`.
+    pub resume_from: Option<TrainPosition>,
+}
+
+impl Default for TrainConfig {
+    fn default() -> Self {
+        Self {
+            epochs: 1,
+            batch_size: 8,
+            random_windows: false,
+            log_every: 10,
+            checkpoint_every: 0,
+            seed: 0,
+            resume_from: None,
+        }
+    }
+}
+
+// How far training got. Saved in checkpoints so a resumed run visits the
+// same windows, in the same order, as one that never stopped.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TrainPosition {
+    // Epochs finished.
+    pub epoch: i32,
+    // Windows of the current epoch already trained on.
+    pub window: usize,
+    // Fixes the window order when windows are drawn at random.
+    pub seed: u64,
+}
+
+pub struct TrainStats {
+    pub steps: i32,
+    pub tokens_seen: usize,
+    pub final_loss: f32,
+    pub elapsed_secs: f32,
+    // Where this run stopped, to resume from later.
+    pub position: TrainPosition,
+}
+
+// What `train` hands its progress callback every `log_every` steps.
+pub struct TrainProgress {
+    pub epoch: i32,
+    pub step: i32,
+    // Mean loss over the steps since the previous report.
+    pub loss: f32,
+    pub tokens_per_sec: f32,
+    pub elapsed_secs: f32,
+}
+
+// Start offsets of the windows visited in one epoch. Each window spans
+// `seq_len + 1` tokens so that every input position has a target.
+fn window_starts(corpus_len: usize, seq_len: usize, random: bool, rng: &mut StdRng) -> Vec<usize> {
+    if corpus_len < 2 {
+        return vec![];
+    }
+
+    let num_windows = (corpus_len - 1).div_ceil(seq_len);
+    if random {
+        let max_start = corpus_len.saturating_sub(seq_len + 1);
+        (0..num_windows)
+            .map(|_| rng.gen_range(0..=max_start))
+            .collect()
+    } else {
+        (0..num_windows).map(|w| w * seq_len).collect()
+    }
+}
+
+// Runs `config.epochs` epochs in total, counting those a resumed run already
+// finished. Every `config.checkpoint_every` steps `on_checkpoint` gets the
+// weights, the optimizer and the position to resume from, so it can save a
+// checkpoint; an error from it stops training.
+pub fn train(
+    model: &mut Model,
+    optimizer: &mut Optimizer,
+    corpus: &[u32],
+    config: &TrainConfig,
+    mut on_progress: impl FnMut(&TrainProgress),
+    mut on_checkpoint: impl FnMut(&Model, &Optimizer, &TrainPosition) -> Result<(), ErrorE>,
+) -> Result<TrainStats, ErrorE> {
+    let seq_len = model.config.seq_len as usize;
+    let batch_size = config.batch_size.max(1) as usize;
+    let mut position = config.resume_from.unwrap_or(TrainPosition {
+        seed: config.seed,
+        ..TrainPosition::default()
+    });
+    let mut rng = StdRng::seed_from_u64(position.seed);
+    let start_time = Instant::now();
+
+    let mut steps = 0i32;
+    let mut tokens_seen = 0usize;
+    let mut running_loss = 0f32;
+    let mut running_steps = 0i32;
+    let mut final_loss = 0f32;
+
+    for epoch in 0..config.epochs {
+        // Epochs that are already done still draw their windows, which
+        // brings the RNG to where the stopped run left it.
+        let starts = window_starts(corpus.len(), seq_len, config.random_windows, &mut rng);
+        if epoch < position.epoch {
+            continue;
+        }
+        position.window = position.window.min(starts.len());
+        let first_window = position.window;
+
+        for batch in starts[first_window..].chunks(batch_size) {
+            let mut tape = Tape::new();
+            let mut batch_loss = None;
+
+            for start in batch {
+                let end = (start + seq_len + 1).min(corpus.len());
+                let (vocab_pred, target_token_ids) =
+                    model.try_forward(&mut tape, &corpus[*start..end])?;
+                tokens_seen += target_token_ids.len();
+
+                let loss = model.cross_entropy(&mut tape, vocab_pred, target_token_ids);
+                batch_loss = Some(match batch_loss {
+                    Some(total) => tape.add(total, loss),
+                    None => loss,
+                });
+            }
+
+            position.window += batch.len();
+            let Some(batch_loss) = batch_loss else {
+                continue;
+            };
+            let loss = tape.div(batch_loss, batch.len() as f32);
+            tape.backward(loss);
+
+            let grads = tape.param_grads();
+            optimizer.try_step(model.params_mut(), &grads)?;
+
+            final_loss = tape.value(loss).vals[0];
+            running_loss += final_loss;
+            running_steps += 1;
+            steps += 1;
+
+            if config.log_every > 0 && steps % config.log_every == 0 {
+                let elapsed_secs = start_time.elapsed().as_secs_f32();
+                on_progress(&TrainProgress {
+                    epoch: epoch + 1,
+                    step: steps,
+                    loss: running_loss / running_steps as f32,
+                    tokens_per_sec: tokens_seen as f32 / elapsed_secs,
+                    elapsed_secs,
+                });
+                running_loss = 0.0;
+                running_steps = 0;
+            }
+            if config.checkpoint_every > 0 && steps % config.checkpoint_every == 0 {
+                on_checkpoint(model, optimizer, &position)?;
+            }
+        }
+
+        position = TrainPosition {
+            epoch: epoch + 1,
+            window: 0,
+            seed: position.seed,
+        };
+    }
+
+    Ok(TrainStats {
+        steps,
+        tokens_seen,
+        final_loss,
+        elapsed_secs: start_time.elapsed().as_secs_f32(),
+        position,
+    })
+}
+
+pub struct EvalStats {
+    pub tokens: usize,
+    pub loss: f32,
+    pub perplexity: f32,
+}
+
+// Mean cross-entropy per predicted token over consecutive windows of
+// `corpus`, without touching the weights.
+pub fn evaluate(model: &Model, corpus: &[u32]) -> Result<EvalStats, ErrorE> {
+    let seq_len = model.config.seq_len as usize;
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut total_loss = 0f64;
+    let mut tokens = 0usize;
+
+    for start in window_starts(corpus.len(), seq_len, false, &mut rng) {
+        let end = (start + seq_len + 1).min(corpus.len());
+        let mut tape = Tape::new();
+        let (vocab_pred, target_token_ids) = model.try_forward(&mut tape, &corpus[start..end])?;
+        let num_targets = target_token_ids.len();
+
+        let loss = model.cross_entropy(&mut tape, vocab_pred, target_token_ids);
+        total_loss += tape.value(loss).vals[0] as f64 * num_targets as f64;
+        tokens += num_targets;
+    }
+
+    let loss = if tokens > 0 {
+        (total_loss / tokens as f64) as f32
+    } else {
+        0.0
+    };
+    Ok(EvalStats {
+        tokens,
+        loss,
+        perplexity: loss.exp(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        checkpoint::{load_checkpoint, save_checkpoint},
+        test_utils::{temp_path, test_model},
+    };
+
+    // Stops a run partway through its first epoch by saving a checkpoint and
+    // training on from it, which has to land on the same weights as a run
+    // that never stopped.
+    #[test]
+    fn resuming_mid_epoch_matches_an_uninterrupted_run() {
+        let corpus: Vec<u32> = (0..100).map(|i| (i * 7 + 3) % 20).collect();
+        let config = TrainConfig {
+            epochs: 2,
+            batch_size: 4,
+            random_windows: true,
+            log_every: 0,
+            seed: 7,
+            ..TrainConfig::default()
+        };
+
+        let mut model = test_model(0);
+        let mut optimizer = Optimizer::adamw(0.01, 0.01);
+        let full = train(
+            &mut model,
+            &mut optimizer,
+            &corpus,
+            &config,
+            |_| {},
+            |_, _, _| Ok(()),
+        )
+        .unwrap();
+
+        let path = temp_path("resume.ckpt");
+        let mut saved: Option<TrainPosition> = None;
+        let mut stopped_model = test_model(0);
+        let mut stopped_optimizer = Optimizer::adamw(0.01, 0.01);
+        train(
+            &mut stopped_model,
+            &mut stopped_optimizer,
+            &corpus,
+            &TrainConfig {
+                checkpoint_every: 3,
+                ..config
+            },
+            |_| {},
+            |model, optimizer, position| {
+                if saved.is_none() {
+                    saved = Some(*position);
+                    save_checkpoint(&path, model, Some(optimizer), Some(position))?;
+                }
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(saved.map(|position| position.epoch), Some(0));
+        assert_eq!(saved.map(|position| position.window), Some(12));
+
+        let (mut resumed, optimizer, position) = load_checkpoint(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(position, saved);
+        let mut optimizer = optimizer.unwrap();
+        let stats = train(
+            &mut resumed,
+            &mut optimizer,
+            &corpus,
+            &TrainConfig {
+                resume_from: position,
+                ..config
+            },
+            |_| {},
+            |_, _, _| Ok(()),
+        )
+        .unwrap();
+
+        assert_eq!(stats.position, full.position);
+        assert_eq!(stats.final_loss, full.final_loss);
+        for (resumed, full) in resumed.params().iter().zip(model.params().iter()) {
+            assert_eq!(resumed.name, full.name);
+            assert_eq!(resumed.vals, full.vals, "{}", full.name);
+        }
+    }
+}
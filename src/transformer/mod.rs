@@ -1,11 +1,16 @@
 use crate::{
-    attention::{AttentionParams, attention},
-    utils::{MatrixF32, NeuralNetwork},
+    attention::{AttentionParams, KVCache, attention},
+    autograd::{Param, ParamMut, Tape, Var},
+    config::ModelConfig,
+    utils::{FeedForwardKindE, GatedFeedForward, NNActivationE, NeuralNetwork},
 };
 
+pub enum FeedForwardE {
+    FeedForwardNN(NeuralNetwork),
+    FeedForwardGated(GatedFeedForward),
+}
+
 pub struct Transformer {
-    pub dim: i32,
-    pub seq_len: i32,
     pub attention_eps: f32,
     pub attention_beta: Vec<f32>,
     pub attention_gamma: Vec<f32>,
@@ -13,41 +18,117 @@ pub struct Transformer {
     pub ff_beta: Vec<f32>,
     pub ff_gamma: Vec<f32>,
     pub attention_params: AttentionParams,
-    pub nn: NeuralNetwork,
+    pub feed_forward: FeedForwardE,
 }
 
 impl Transformer {
-    pub fn new(dim: i32, seq_len: i32) -> Self {
-        let nn_hidden_nodes = 32;
-        let mut nn = NeuralNetwork::new(seq_len, dim);
-        nn.add_layer(nn_hidden_nodes, dim);
-        nn.add_layer(dim, nn_hidden_nodes);
+    // The MLP's hidden layer uses the kind's activation and the projection
+    // back to `dim` stays linear.
+    pub fn new(config: &ModelConfig) -> Self {
+        let dim = config.dim;
+        let hidden_nodes = config.hidden_nodes;
+        let feed_forward = match config.feed_forward {
+            FeedForwardKindE::FeedForwardMLP { activation } => {
+                let mut nn = NeuralNetwork::new(dim, dim);
+                nn.add_layer(hidden_nodes, dim, activation);
+                nn.add_layer(dim, hidden_nodes, NNActivationE::NNActivationLinear);
+                FeedForwardE::FeedForwardNN(nn)
+            }
+            FeedForwardKindE::FeedForwardGLU { activation } => {
+                FeedForwardE::FeedForwardGated(GatedFeedForward::new(dim, hidden_nodes, activation))
+            }
+        };
 
         Self {
-            dim,
-            seq_len,
-            attention_params: AttentionParams::new(dim as usize),
-            nn,
-            attention_eps: 0.003f32,
+            attention_params: AttentionParams::new(dim as usize, config.n_heads as usize),
+            feed_forward,
+            attention_eps: config.eps,
             attention_gamma: vec![1.0f32; dim as usize],
             attention_beta: vec![0.0f32; dim as usize],
-            ff_eps: 0.003f32,
+            ff_eps: config.eps,
             ff_gamma: vec![1.0f32; dim as usize],
             ff_beta: vec![0.0f32; dim as usize],
         }
     }
 
-    pub fn run(self: &mut Transformer, seq: &MatrixF32) -> MatrixF32 {
-        let (_attention_params, mut output) = attention(self.dim, &seq);
-        output = output.layer_norm(
-            self.attention_eps,
+    pub fn params<'a>(&'a self, prefix: &str, params: &mut Vec<Param<'a>>) {
+        params.push(Param::vector(
+            format!("{}.attn_gamma", prefix),
             &self.attention_gamma,
+        ));
+        params.push(Param::vector(
+            format!("{}.attn_beta", prefix),
             &self.attention_beta,
+        ));
+        params.push(Param::vector(
+            format!("{}.ff_gamma", prefix),
+            &self.ff_gamma,
+        ));
+        params.push(Param::vector(format!("{}.ff_beta", prefix), &self.ff_beta));
+        self.attention_params
+            .params(&format!("{}.attn", prefix), params);
+        match &self.feed_forward {
+            FeedForwardE::FeedForwardNN(nn) => nn.params(&format!("{}.nn", prefix), params),
+            FeedForwardE::FeedForwardGated(ff) => ff.params(&format!("{}.ff", prefix), params),
+        }
+    }
+
+    pub fn params_mut<'a>(&'a mut self, prefix: &str, params: &mut Vec<ParamMut<'a>>) {
+        params.push(ParamMut::vector(
+            format!("{}.attn_gamma", prefix),
+            &mut self.attention_gamma,
+        ));
+        params.push(ParamMut::vector(
+            format!("{}.attn_beta", prefix),
+            &mut self.attention_beta,
+        ));
+        params.push(ParamMut::vector(
+            format!("{}.ff_gamma", prefix),
+            &mut self.ff_gamma,
+        ));
+        params.push(ParamMut::vector(
+            format!("{}.ff_beta", prefix),
+            &mut self.ff_beta,
+        ));
+        self.attention_params
+            .params_mut(&format!("{}.attn", prefix), params);
+        match &mut self.feed_forward {
+            FeedForwardE::FeedForwardNN(nn) => nn.params_mut(&format!("{}.nn", prefix), params),
+            FeedForwardE::FeedForwardGated(ff) => ff.params_mut(&format!("{}.ff", prefix), params),
+        }
+    }
+
+    pub fn run(
+        self: &Transformer,
+        tape: &mut Tape,
+        prefix: &str,
+        seq: Var,
+        cache: Option<&mut KVCache>,
+    ) -> Var {
+        let attention_gamma =
+            tape.param_vec(&format!("{}.attn_gamma", prefix), &self.attention_gamma);
+        let attention_beta = tape.param_vec(&format!("{}.attn_beta", prefix), &self.attention_beta);
+        let ff_gamma = tape.param_vec(&format!("{}.ff_gamma", prefix), &self.ff_gamma);
+        let ff_beta = tape.param_vec(&format!("{}.ff_beta", prefix), &self.ff_beta);
+
+        let mut output = attention(
+            tape,
+            &format!("{}.attn", prefix),
+            &self.attention_params,
+            seq,
+            cache,
         );
+        output = tape.layer_norm(output, self.attention_eps, attention_gamma, attention_beta);
 
-        let mut nn_output = self.nn.feed_forward(&output);
-        nn_output = &nn_output + &output;
-        nn_output.layer_norm(self.ff_eps, &self.ff_gamma, &self.ff_beta);
-        nn_output
+        let mut nn_output = match &self.feed_forward {
+            FeedForwardE::FeedForwardNN(nn) => {
+                nn.feed_forward(tape, &format!("{}.nn", prefix), output)
+            }
+            FeedForwardE::FeedForwardGated(ff) => {
+                ff.feed_forward(tape, &format!("{}.ff", prefix), output)
+            }
+        };
+        nn_output = tape.add(nn_output, output);
+        tape.layer_norm(nn_output, self.ff_eps, ff_gamma, ff_beta)
     }
 }
@@ -1,35 +1,30 @@
-use core::{f32, fmt};
-use std::collections::HashSet;
-use std::fs::OpenOptions;
-use std::io::{Read, Seek};
+use core::fmt;
+use std::cell::RefCell;
+use std::fs;
 use std::ops::{Add, Div, Mul};
 use std::ops::{Index, IndexMut};
-use std::process::Output;
-use std::thread::panicking;
-use std::usize;
 
-use rand::thread_rng;
-use rand_distr::uniform::SampleBorrow;
+use rand::{SeedableRng, rngs::StdRng};
 use rand_distr::{Distribution, Uniform};
 
-use crate::tokenizer::TokenRef;
+use crate::{
+    autograd::{Param, ParamMut, Tape, Var},
+    error::ErrorE,
+    parallel::for_each_row_chunk,
+};
 
-#[derive(Debug)]
-pub struct NiceError {
-    message: String,
+thread_local! {
+    // Weight initialisation draws from here so a run can be made repeatable
+    // with `seed_rng`.
+    static INIT_RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
 }
 
-impl NiceError {
-    pub fn new(message: String) -> NiceError {
-        eprintln!("{}", message);
-        let error = NiceError { message };
-        error
-    }
+pub fn seed_rng(seed: u64) {
+    INIT_RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+}
 
-    pub fn show(self) -> NiceError {
-        eprintln!("{}", self.message);
-        self
-    }
+pub fn with_rng<T>(f: impl FnOnce(&mut StdRng) -> T) -> T {
+    INIT_RNG.with(|rng| f(&mut rng.borrow_mut()))
 }
 
 #[derive(Clone)]
@@ -72,16 +67,7 @@ impl MatrixF32 {
     pub fn new_rand_weight(rows: usize, cols: usize) -> Self {
         let limit = (6.0 / (rows as f32 + cols as f32)).sqrt();
         let uniform = Uniform::new(-limit, limit);
-        let mut rng = thread_rng();
-
-        let vals = (0..rows)
-            .map(|_| {
-                (0..cols)
-                    .map(|_| uniform.sample(&mut rng))
-                    .collect::<Vec<f32>>()
-            })
-            .flatten()
-            .collect();
+        let vals = with_rng(|rng| (0..rows * cols).map(|_| uniform.sample(rng)).collect());
 
         Self {
             rows: rows as i32,
@@ -90,70 +76,86 @@ impl MatrixF32 {
         }
     }
 
-    pub fn transpose(&mut self) {
-        for i in 0..self.rows {
-            for j in 0..self.cols {
-                let prev_ij = self[(i, j)];
-                self[(i, j)] = self[(j, i)];
-                self[(j, i)] = prev_ij;
-            }
+    pub fn view(self: &MatrixF32) -> MatrixViewF32<'_> {
+        MatrixViewF32 {
+            rows: self.rows,
+            cols: self.cols,
+            vals: &self.vals,
+            row_stride: self.cols as usize,
+            col_stride: 1,
         }
-        let prev_rows = self.rows;
-        self.rows = self.cols;
-        self.cols = prev_rows;
     }
 
+    // Transposed view that shares this matrix's values.
+    pub fn t(self: &MatrixF32) -> MatrixViewF32<'_> {
+        self.view().t()
+    }
+
+    pub fn transposed(self: &MatrixF32) -> MatrixF32 {
+        self.t().to_matrix()
+    }
+
+    pub fn transpose(&mut self) {
+        *self = self.transposed();
+    }
+
+    // With more columns than rows (new queries against cached keys) the last
+    // row lines up with the last column.
     pub fn casual_mask(&mut self) {
+        let offset = (self.cols - self.rows).max(0);
         for i in 0..self.rows {
-            for j in (i + 1)..self.cols {
+            for j in (i + 1 + offset)..self.cols {
                 self[(i, j)] = f32::NEG_INFINITY;
             }
         }
     }
 
     pub fn softmax_row(&mut self) {
-        for i in 0..self.rows {
-            let mut max = f32::NEG_INFINITY;
-            for j in 0..self.cols {
-                max = f32::max(max, self[(i, j)]);
-            }
-
-            let mut exp_sum = 0.0;
-            for j in 0..self.cols {
-                exp_sum += (self[(i, j)] - max).exp()
-            }
+        let cols = self.cols as usize;
+        for_each_row_chunk(&mut self.vals, cols, cols * 4, |_, rows| {
+            for row in rows.chunks_exact_mut(cols) {
+                let max = row
+                    .iter()
+                    .fold(f32::NEG_INFINITY, |max, v| f32::max(max, *v));
+
+                let mut exp_sum = 0.0;
+                for val in row.iter() {
+                    exp_sum += (val - max).exp()
+                }
 
-            for j in 0..self.cols {
-                self[(i, j)] = (self[(i, j)] - max).exp() / exp_sum;
+                for val in row.iter_mut() {
+                    *val = (*val - max).exp() / exp_sum;
+                }
             }
-        }
+        });
     }
 
-    pub fn layer_norm(self: &MatrixF32, eps: f32, gamma: &Vec<f32>, beta: &Vec<f32>) -> MatrixF32 {
-        let mut norm = MatrixF32::new(self.rows, self.cols);
-        let dim = self.cols;
+    pub fn layer_norm(self: &MatrixF32, eps: f32, gamma: &[f32], beta: &[f32]) -> MatrixF32 {
+        let mut norm = self.clone();
+        let dim = self.cols as usize;
 
-        for i in 0..self.rows {
-            let mut sum = 0f32;
-            for j in 0..self.cols {
-                sum += self[(i, j)];
-            }
+        for_each_row_chunk(&mut norm.vals, dim, dim * 4, |_, rows| {
+            for row in rows.chunks_exact_mut(dim) {
+                let mut sum = 0f32;
+                for val in row.iter() {
+                    sum += val;
+                }
 
-            let mean = sum / (dim as f32);
+                let mean = sum / (dim as f32);
 
-            let mut sum = 0f32;
-            for j in 0..self.cols {
-                sum += (self[(i, j)] - mean).powi(2);
-            }
+                let mut sum = 0f32;
+                for val in row.iter() {
+                    sum += (val - mean).powi(2);
+                }
 
-            let variance = sum / (dim as f32);
-            let stddev = (variance + eps).sqrt();
+                let variance = sum / (dim as f32);
+                let stddev = (variance + eps).sqrt();
 
-            for j in 0..self.cols {
-                norm[(i, j)] =
-                    gamma[j as usize] * ((self[(i, j)] - mean) / stddev) + beta[j as usize];
+                for (j, val) in row.iter_mut().enumerate() {
+                    *val = gamma[j] * ((*val - mean) / stddev) + beta[j];
+                }
             }
-        }
+        });
 
         norm
     }
@@ -175,57 +177,215 @@ impl IndexMut<(i32, i32)> for MatrixF32 {
     }
 }
 
-impl Mul for &MatrixF32 {
-    type Output = MatrixF32;
+// A borrowed, strided window onto a matrix's values. Transposing a view just
+// swaps its strides, so `a.t()` can be fed to a matmul without a copy.
+#[derive(Clone, Copy)]
+pub struct MatrixViewF32<'a> {
+    pub rows: i32,
+    pub cols: i32,
+    vals: &'a [f32],
+    row_stride: usize,
+    col_stride: usize,
+}
 
-    fn mul(self, other: &MatrixF32) -> MatrixF32 {
-        if self.cols != other.rows {
-            panic!("[matrix_multiplication] dimensions don't match");
+impl<'a> MatrixViewF32<'a> {
+    pub fn t(self: MatrixViewF32<'a>) -> MatrixViewF32<'a> {
+        MatrixViewF32 {
+            rows: self.cols,
+            cols: self.rows,
+            vals: self.vals,
+            row_stride: self.col_stride,
+            col_stride: self.row_stride,
+        }
+    }
+
+    pub fn get(self: &MatrixViewF32<'a>, i: i32, j: i32) -> f32 {
+        self.vals[i as usize * self.row_stride + j as usize * self.col_stride]
+    }
+
+    pub fn to_matrix(self: &MatrixViewF32<'a>) -> MatrixF32 {
+        let mut vals = Vec::with_capacity((self.rows * self.cols) as usize);
+        for i in 0..self.rows as usize {
+            self.extend_row(i, 0, self.cols as usize, &mut vals);
         }
 
-        let c_rows = self.rows;
-        let c_cols = other.cols;
-        let mut c = MatrixF32::new(c_rows, c_cols);
+        MatrixF32 {
+            rows: self.rows,
+            cols: self.cols,
+            vals,
+        }
+    }
 
-        let a_rows = self.rows;
-        let a_cols = self.cols;
-        let b_cols = other.cols;
+    // `len` values of row `i` starting at column `start`, if they're
+    // contiguous in memory.
+    fn row_slice(
+        self: &MatrixViewF32<'a>,
+        i: usize,
+        start: usize,
+        len: usize,
+    ) -> Option<&'a [f32]> {
+        if self.col_stride != 1 {
+            return None;
+        }
+        let offset = i * self.row_stride + start;
+        Some(&self.vals[offset..offset + len])
+    }
 
-        for a_row in 0..a_rows {
-            for b_col in 0..b_cols {
-                for a_col in 0..a_cols {
-                    c[(a_row, b_col)] += self[(a_row, a_col)] * other[(a_col, b_col)];
-                }
+    fn extend_row(
+        self: &MatrixViewF32<'a>,
+        i: usize,
+        start: usize,
+        len: usize,
+        out: &mut Vec<f32>,
+    ) {
+        match self.row_slice(i, start, len) {
+            Some(row) => out.extend_from_slice(row),
+            None => {
+                let offset = i * self.row_stride + start * self.col_stride;
+                out.extend((0..len).map(|j| self.vals[offset + j * self.col_stride]));
             }
         }
+    }
 
-        c
+    pub fn checked_mul(
+        self: &MatrixViewF32<'a>,
+        other: &MatrixViewF32,
+    ) -> Result<MatrixF32, ErrorE> {
+        if self.cols != other.rows {
+            return Err(ErrorE::shape_mismatch(
+                "matrix_multiplication",
+                (self.rows, self.cols),
+                (other.rows, other.cols),
+            ));
+        }
+
+        let (k, n) = (self.cols as usize, other.cols as usize);
+        let mut c = MatrixF32::new(self.rows, other.cols);
+        for_each_row_chunk(&mut c.vals, n, k * n, |first_row, c_rows| {
+            matmul_blocked(self, other, c_rows, first_row);
+        });
+
+        Ok(c)
     }
 }
 
-impl Add for &MatrixF32 {
-    type Output = MatrixF32;
+impl MatrixF32 {
+    // Fallible versions of `*` and `+`, which panic on a shape mismatch.
+    pub fn checked_mul(self: &MatrixF32, other: &MatrixF32) -> Result<MatrixF32, ErrorE> {
+        self.view().checked_mul(&other.view())
+    }
 
-    fn add(self, other: &MatrixF32) -> MatrixF32 {
+    pub fn checked_add(self: &MatrixF32, other: &MatrixF32) -> Result<MatrixF32, ErrorE> {
         if self.rows != other.rows || self.cols != other.cols {
-            panic!("[matrix_addition] dimensions don't match");
+            return Err(ErrorE::shape_mismatch(
+                "matrix_addition",
+                (self.rows, self.cols),
+                (other.rows, other.cols),
+            ));
         }
 
-        let c_rows = self.rows;
-        let c_cols = self.cols;
-        let mut c = MatrixF32::new(c_rows, c_cols);
+        let cols = self.cols as usize;
+        let mut c = self.clone();
+        for_each_row_chunk(&mut c.vals, cols, cols, |first_row, rows| {
+            let start = first_row * cols;
+            let other_rows = &other.vals[start..start + rows.len()];
+            for (c_ij, other_ij) in rows.iter_mut().zip(other_rows) {
+                *c_ij += other_ij;
+            }
+        });
 
-        for i in 0..self.rows {
-            for j in 0..self.cols {
-                c[(i, j)] = self[(i, j)] + other[(i, j)]
+        Ok(c)
+    }
+}
+
+// Tile sizes for `matmul_blocked`: a panel of B is MATMUL_PANEL_COLS wide
+// and MATMUL_BLOCK_INNER deep, and is reused across MATMUL_BLOCK_ROWS rows of
+// A while it is still in cache.
+const MATMUL_BLOCK_ROWS: usize = 32;
+const MATMUL_BLOCK_INNER: usize = 128;
+const MATMUL_PANEL_COLS: usize = 256;
+
+// c += rows `first_row..` of a (m * k) times b (k * n), where c is row-major
+// and holds however many rows of the product this call is responsible for.
+//
+// Columns of B are packed into contiguous panels so the inner loop is a
+// straight `c_row += a_ik * b_row` over slices, which the compiler turns
+// into SIMD. Every element of C still sums its k terms in ascending order,
+// so the result matches the naive triple loop exactly. Strided (transposed)
+// operands are gathered into the panel or a row buffer as they're packed.
+fn matmul_blocked(a: &MatrixViewF32, b: &MatrixViewF32, c: &mut [f32], first_row: usize) {
+    let (k, n) = (a.cols as usize, b.cols as usize);
+    if n == 0 {
+        return;
+    }
+    let m = c.len() / n;
+    let mut panel: Vec<f32> = Vec::with_capacity(k * MATMUL_PANEL_COLS.min(n));
+    let mut a_buf: Vec<f32> = Vec::with_capacity(MATMUL_BLOCK_INNER.min(k));
+
+    for j0 in (0..n).step_by(MATMUL_PANEL_COLS) {
+        let nb = MATMUL_PANEL_COLS.min(n - j0);
+        panel.clear();
+        for kk in 0..k {
+            b.extend_row(kk, j0, nb, &mut panel);
+        }
+
+        for k0 in (0..k).step_by(MATMUL_BLOCK_INNER) {
+            let kb = MATMUL_BLOCK_INNER.min(k - k0);
+
+            for i0 in (0..m).step_by(MATMUL_BLOCK_ROWS) {
+                for i in i0..MATMUL_BLOCK_ROWS.min(m - i0) + i0 {
+                    let a_row = match a.row_slice(first_row + i, k0, kb) {
+                        Some(row) => row,
+                        None => {
+                            a_buf.clear();
+                            a.extend_row(first_row + i, k0, kb, &mut a_buf);
+                            &a_buf
+                        }
+                    };
+                    let c_row = &mut c[i * n + j0..i * n + j0 + nb];
+
+                    for (kk, a_ik) in a_row.iter().enumerate() {
+                        let b_row = &panel[(k0 + kk) * nb..(k0 + kk + 1) * nb];
+                        for (c_ij, b_kj) in c_row.iter_mut().zip(b_row) {
+                            *c_ij += a_ik * b_kj;
+                        }
+                    }
+                }
             }
         }
+    }
+}
 
-        c
+impl Mul for &MatrixF32 {
+    type Output = MatrixF32;
+
+    fn mul(self, other: &MatrixF32) -> MatrixF32 {
+        self.checked_mul(other)
+            .unwrap_or_else(|error| panic!("{}", error))
     }
 }
 
-impl<'a> Div<f32> for &'a MatrixF32 {
+impl<'a> Mul for MatrixViewF32<'a> {
+    type Output = MatrixF32;
+
+    fn mul(self, other: MatrixViewF32<'a>) -> MatrixF32 {
+        self.checked_mul(&other)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+}
+
+impl Add for &MatrixF32 {
+    type Output = MatrixF32;
+
+    fn add(self, other: &MatrixF32) -> MatrixF32 {
+        self.checked_add(other)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+}
+
+// Panics on a zero divisor. Callers divide by counts they've already checked
+// are non-zero, such as a batch's length.
+impl Div<f32> for &MatrixF32 {
     type Output = MatrixF32;
 
     fn div(self, rhs: f32) -> MatrixF32 {
@@ -243,11 +403,81 @@ impl<'a> Div<f32> for &'a MatrixF32 {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum NNActivationE {
+    NNActivationLinear,
     NNActivationRELU,
     NNActivationGELU,
     NNActivationATAN,
     NNActivationSigmoid,
+    NNActivationSiLU,
+}
+
+// sqrt(2 / pi), for the tanh approximation of GELU.
+const GELU_C: f32 = 0.797_884_6;
+
+impl NNActivationE {
+    pub fn name(self: &NNActivationE) -> &'static str {
+        match self {
+            NNActivationE::NNActivationLinear => "linear",
+            NNActivationE::NNActivationRELU => "relu",
+            NNActivationE::NNActivationGELU => "gelu",
+            NNActivationE::NNActivationATAN => "atan",
+            NNActivationE::NNActivationSigmoid => "sigmoid",
+            NNActivationE::NNActivationSiLU => "silu",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<NNActivationE> {
+        match name {
+            "linear" => Some(NNActivationE::NNActivationLinear),
+            "relu" => Some(NNActivationE::NNActivationRELU),
+            "gelu" => Some(NNActivationE::NNActivationGELU),
+            "atan" => Some(NNActivationE::NNActivationATAN),
+            "sigmoid" => Some(NNActivationE::NNActivationSigmoid),
+            "silu" => Some(NNActivationE::NNActivationSiLU),
+            _ => None,
+        }
+    }
+
+    pub fn apply(self: &NNActivationE, x: f32) -> f32 {
+        match self {
+            NNActivationE::NNActivationLinear => x,
+            NNActivationE::NNActivationRELU => x.max(0.0),
+            NNActivationE::NNActivationGELU => {
+                0.5 * x * (1.0 + (GELU_C * (x + 0.044715 * x.powi(3))).tanh())
+            }
+            NNActivationE::NNActivationATAN => x.atan(),
+            NNActivationE::NNActivationSigmoid => 1.0 / (1.0 + (-x).exp()),
+            NNActivationE::NNActivationSiLU => x / (1.0 + (-x).exp()),
+        }
+    }
+
+    pub fn derivative(self: &NNActivationE, x: f32) -> f32 {
+        match self {
+            NNActivationE::NNActivationLinear => 1.0,
+            NNActivationE::NNActivationRELU => {
+                if x > 0.0 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            NNActivationE::NNActivationGELU => {
+                let t = (GELU_C * (x + 0.044715 * x.powi(3))).tanh();
+                0.5 * (1.0 + t) + 0.5 * x * (1.0 - t * t) * GELU_C * (1.0 + 3.0 * 0.044715 * x * x)
+            }
+            NNActivationE::NNActivationATAN => 1.0 / (1.0 + x * x),
+            NNActivationE::NNActivationSigmoid => {
+                let s = 1.0 / (1.0 + (-x).exp());
+                s * (1.0 - s)
+            }
+            NNActivationE::NNActivationSiLU => {
+                let s = 1.0 / (1.0 + (-x).exp());
+                s + x * s * (1.0 - s)
+            }
+        }
+    }
 }
 
 pub struct NNLayer {
@@ -256,18 +486,16 @@ pub struct NNLayer {
     pub activation_function: NNActivationE,
     pub weights: MatrixF32,
     pub biases: Vec<f32>,
-    pub activations: MatrixF32,
 }
 
 impl NNLayer {
-    pub fn new(num_nodes: i32, dim: i32) -> Self {
+    pub fn new(num_nodes: i32, dim: i32, activation_function: NNActivationE) -> Self {
         Self {
             num_nodes,
             dim,
-            activation_function: NNActivationE::NNActivationRELU,
+            activation_function,
             weights: MatrixF32::new_rand_weight(dim as usize, num_nodes as usize),
             biases: rand_vec(num_nodes, 0.1),
-            activations: MatrixF32::new(dim, num_nodes),
         }
     }
 }
@@ -287,58 +515,233 @@ impl NeuralNetwork {
         }
     }
 
-    pub fn add_layer(self: &mut NeuralNetwork, num_nodes: i32, dim: i32) {
-        self.layers.push(NNLayer::new(num_nodes, dim));
+    // Panics if `dim` isn't the previous layer's `num_nodes` (or `input_dim`
+    // for the first layer). Layers are only added while building a model from
+    // a validated config, where that always holds.
+    pub fn add_layer(
+        self: &mut NeuralNetwork,
+        num_nodes: i32,
+        dim: i32,
+        activation_function: NNActivationE,
+    ) {
+        let input_dim = match self.layers.last() {
+            Some(layer) => layer.num_nodes,
+            None => self.input_dim,
+        };
+        if input_dim != dim {
+            panic!("[add_layer] layer dim doesn't match the previous layer's output");
+        }
+        self.layers
+            .push(NNLayer::new(num_nodes, dim, activation_function));
+    }
+
+    pub fn params<'a>(&'a self, prefix: &str, params: &mut Vec<Param<'a>>) {
+        for (i, layer) in self.layers.iter().enumerate() {
+            params.push(Param::matrix(
+                format!("{}.layers.{}.weights", prefix, i),
+                &layer.weights,
+            ));
+            params.push(Param::vector(
+                format!("{}.layers.{}.biases", prefix, i),
+                &layer.biases,
+            ));
+        }
     }
 
-    pub fn feed_forward(self: &mut NeuralNetwork, x: &MatrixF32) -> MatrixF32 {
+    pub fn params_mut<'a>(&'a mut self, prefix: &str, params: &mut Vec<ParamMut<'a>>) {
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            params.push(ParamMut::matrix(
+                format!("{}.layers.{}.weights", prefix, i),
+                &mut layer.weights,
+            ));
+            params.push(ParamMut::vector(
+                format!("{}.layers.{}.biases", prefix, i),
+                &mut layer.biases,
+            ));
+        }
+    }
+
+    // Panics if `x` doesn't have the first layer's `dim` columns.
+    pub fn feed_forward(self: &NeuralNetwork, tape: &mut Tape, prefix: &str, x: Var) -> Var {
         let mut input = x;
 
-        for layer in self.layers.iter_mut() {
-            layer.activations = input * &layer.weights;
+        for (i, layer) in self.layers.iter().enumerate() {
+            if tape.value(input).cols != layer.dim {
+                panic!("[feed_forward] input doesn't match the layer dim");
+            }
 
-            for i in 0..layer.activations.rows {
-                for j in 0..layer.activations.cols {
-                    layer.activations[(i, j)] += layer.biases[j as usize];
-                }
+            let weights = tape.param(&format!("{}.layers.{}.weights", prefix, i), &layer.weights);
+            let biases = tape.param_vec(&format!("{}.layers.{}.biases", prefix, i), &layer.biases);
+            let activations = tape.matmul(input, weights);
+            input = tape.add_bias(activations, biases);
+            if layer.activation_function != NNActivationE::NNActivationLinear {
+                input = tape.activation(input, layer.activation_function);
             }
+        }
 
-            input = &layer.activations;
+        if tape.value(input).cols != self.output_dim {
+            panic!("[feed_forward] output dim doesn't match the network's output_dim");
         }
 
-        input.clone()
+        input
     }
 }
 
-pub fn read_file(filename: &String) -> Result<String, NiceError> {
-    let mut file = match OpenOptions::new().read(true).open(filename) {
-        Ok(file) => file,
-        Err(error) => {
-            return Err(NiceError::new(format!("Error opening file: {:?}", error)));
-        }
-    };
+// The feed-forward block a transformer uses. Gated variants compute
+// `(activation(x * w_gate) ⊙ (x * w_up)) * w_down`; SwiGLU gates with SiLU
+// and GeGLU with GELU.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FeedForwardKindE {
+    FeedForwardMLP { activation: NNActivationE },
+    FeedForwardGLU { activation: NNActivationE },
+}
 
-    let mut contents = String::new();
+impl FeedForwardKindE {
+    pub fn activation(self: &FeedForwardKindE) -> NNActivationE {
+        match self {
+            FeedForwardKindE::FeedForwardMLP { activation }
+            | FeedForwardKindE::FeedForwardGLU { activation } => *activation,
+        }
+    }
 
-    let _ = match file.read_to_string(&mut contents) {
-        Ok(_) => Ok(()),
-        Err(_) => Err(NiceError::new(format!(
-            "Error opening file: {:?}",
-            filename
-        ))),
-    };
+    pub fn name(self: &FeedForwardKindE) -> &'static str {
+        match self {
+            FeedForwardKindE::FeedForwardMLP { .. } => "mlp",
+            FeedForwardKindE::FeedForwardGLU { .. } => "glu",
+        }
+    }
+}
 
-    Ok(contents)
+pub struct GatedFeedForward {
+    pub dim: i32,
+    pub activation_function: NNActivationE,
+    pub w_gate: MatrixF32,
+    pub w_up: MatrixF32,
+    pub w_down: MatrixF32,
 }
 
-pub fn print_tokens(tokens: &HashSet<TokenRef>) {
-    for token in tokens.iter() {
-        println!("{}", token.0.borrow().val);
+impl GatedFeedForward {
+    pub fn new(dim: i32, hidden_dim: i32, activation_function: NNActivationE) -> Self {
+        Self {
+            dim,
+            activation_function,
+            w_gate: MatrixF32::new_rand_weight(dim as usize, hidden_dim as usize),
+            w_up: MatrixF32::new_rand_weight(dim as usize, hidden_dim as usize),
+            w_down: MatrixF32::new_rand_weight(hidden_dim as usize, dim as usize),
+        }
+    }
+
+    pub fn params<'a>(&'a self, prefix: &str, params: &mut Vec<Param<'a>>) {
+        params.push(Param::matrix(format!("{}.w_gate", prefix), &self.w_gate));
+        params.push(Param::matrix(format!("{}.w_up", prefix), &self.w_up));
+        params.push(Param::matrix(format!("{}.w_down", prefix), &self.w_down));
+    }
+
+    pub fn params_mut<'a>(&'a mut self, prefix: &str, params: &mut Vec<ParamMut<'a>>) {
+        params.push(ParamMut::matrix(
+            format!("{}.w_gate", prefix),
+            &mut self.w_gate,
+        ));
+        params.push(ParamMut::matrix(format!("{}.w_up", prefix), &mut self.w_up));
+        params.push(ParamMut::matrix(
+            format!("{}.w_down", prefix),
+            &mut self.w_down,
+        ));
+    }
+
+    pub fn feed_forward(self: &GatedFeedForward, tape: &mut Tape, prefix: &str, x: Var) -> Var {
+        if tape.value(x).cols != self.dim {
+            panic!("[gated_feed_forward] input doesn't match the dim");
+        }
+
+        let w_gate = tape.param(&format!("{}.w_gate", prefix), &self.w_gate);
+        let w_up = tape.param(&format!("{}.w_up", prefix), &self.w_up);
+        let w_down = tape.param(&format!("{}.w_down", prefix), &self.w_down);
+
+        let mut gate = tape.matmul(x, w_gate); // L * H
+        gate = tape.activation(gate, self.activation_function);
+        let up = tape.matmul(x, w_up); // L * H
+        let hidden = tape.mul_elem(gate, up);
+        tape.matmul(hidden, w_down) // L * D
     }
 }
 
+pub fn read_file(filename: &str) -> Result<String, ErrorE> {
+    fs::read_to_string(filename).map_err(|error| ErrorE::io(filename, error))
+}
+
 pub fn rand_vec(dim: i32, bound: f32) -> Vec<f32> {
     let range = Uniform::new(-bound, bound);
-    let mut rng = rand::thread_rng();
-    (0..dim).map(|_| range.sample(&mut rng)).collect()
+    with_rng(|rng| (0..dim).map(|_| range.sample(rng)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naive_matmul(a: &MatrixViewF32, b: &MatrixViewF32) -> MatrixF32 {
+        let mut c = MatrixF32::new(a.rows, b.cols);
+        for i in 0..a.rows {
+            for j in 0..b.cols {
+                let mut sum = 0f32;
+                for k in 0..a.cols {
+                    sum += a.get(i, k) * b.get(k, j);
+                }
+                c[(i, j)] = sum;
+            }
+        }
+        c
+    }
+
+    #[test]
+    fn blocked_matmul_matches_naive_loop() {
+        seed_rng(0);
+        // Straddles each of the block sizes, plus empty operands.
+        let shapes = [
+            (1, 1, 1),
+            (3, 5, 2),
+            (
+                MATMUL_BLOCK_ROWS + 1,
+                MATMUL_BLOCK_INNER + 3,
+                MATMUL_PANEL_COLS + 7,
+            ),
+            (2 * MATMUL_BLOCK_ROWS - 1, 2 * MATMUL_BLOCK_INNER + 1, 17),
+            (0, 4, 3),
+            (4, 3, 0),
+            (4, 0, 3),
+        ];
+
+        for (m, k, n) in shapes {
+            let a = MatrixF32::new_rand_weight(m, k);
+            let b = MatrixF32::new_rand_weight(k, n);
+            let a_t = a.transposed();
+            let b_t = b.transposed();
+            let expected = naive_matmul(&a.view(), &b.view());
+
+            let operands = [
+                (a.view(), b.view()),
+                (a_t.t(), b.view()),
+                (a.view(), b_t.t()),
+                (a_t.t(), b_t.t()),
+            ];
+            for (a, b) in operands {
+                let c = a.checked_mul(&b).unwrap();
+                assert_eq!((c.rows, c.cols), (m as i32, n as i32));
+                assert_eq!(c.vals, expected.vals, "{}x{} times {}x{}", m, k, k, n);
+            }
+        }
+    }
+
+    #[test]
+    fn blocked_matmul_starts_at_first_row() {
+        seed_rng(1);
+        let a = MatrixF32::new_rand_weight(MATMUL_BLOCK_ROWS + 9, 40);
+        let b = MatrixF32::new_rand_weight(40, 11);
+        let expected = naive_matmul(&a.view(), &b.view());
+
+        let first_row = 5;
+        let mut c = vec![0f32; (a.rows as usize - first_row) * 11];
+        matmul_blocked(&a.view(), &b.view(), &mut c, first_row);
+        assert_eq!(c, expected.vals[first_row * 11..]);
+    }
 }